@@ -0,0 +1,58 @@
+//! Times deserializing a single region's response and a bulk one for every
+//! region, as a baseline for changes to the parsing. Run it with
+//! `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+// There's no library target, so this pulls in the binary's source instead.
+#[path = "../src/main.rs"]
+#[allow(dead_code, private_interfaces)]
+mod carbon_alert;
+
+use carbon_alert::{BulkRegionalResponse, RegionalResponse};
+
+const LONDON_FIXTURE: &str = include_str!("../fixtures/london.json");
+
+/// A response of the `/regional` endpoint, with all 17 regions plus the
+/// aggregate one for GB, each with the London fixture's figures.
+fn bulk_fixture() -> String {
+    let regional: serde_json::Value = serde_json::from_str(LONDON_FIXTURE).unwrap();
+    let slot = &regional["data"][0]["data"][0];
+    let regions: Vec<_> = (1..=18)
+        .map(|id| {
+            serde_json::json!({
+                "regionid": id,
+                "dnoregion": "UKPN London",
+                "shortname": "London",
+                "intensity": slot["intensity"],
+                "generationmix": slot["generationmix"],
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "data": [{
+            "from": slot["from"],
+            "to": slot["to"],
+            "regions": regions,
+        }]
+    })
+    .to_string()
+}
+
+fn deserialize(c: &mut Criterion) {
+    let bulk = bulk_fixture();
+    let mut group = c.benchmark_group("deserialize");
+    // Like `reqwest::Response::json`.
+    group.throughput(Throughput::Bytes(LONDON_FIXTURE.len() as u64));
+    group.bench_function("single region", |b| {
+        b.iter(|| serde_json::from_slice::<RegionalResponse>(LONDON_FIXTURE.as_bytes()).unwrap())
+    });
+    group.throughput(Throughput::Bytes(bulk.len() as u64));
+    group.bench_function("all regions", |b| {
+        b.iter(|| serde_json::from_slice::<BulkRegionalResponse>(bulk.as_bytes()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, deserialize);
+criterion_main!(benches);
@@ -1,27 +1,1318 @@
 use futures_util::stream::StreamExt;
-use std::time::Duration;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, structopt::StructOpt)]
+struct Opt {
+    /// Path to the RON configuration file, or `-` to read it from stdin.
+    config: PathBuf,
+    /// How often to retry reading the config file, with backoff starting at
+    /// a second, e.g. for a network mount that isn't ready yet at boot.
+    #[structopt(long, default_value = "0")]
+    config_read_retries: u32,
+    /// Prefix of the environment variables overriding config values. Takes
+    /// precedence over `env_prefix` in the config.
+    #[structopt(long)]
+    env_prefix: Option<String>,
+    /// Comma-separated region IDs to poll instead of the configured ones.
+    /// The first one becomes the primary region.
+    #[structopt(long, require_delimiter = true, parse(try_from_str = parse_region))]
+    regions: Vec<RegionId>,
+    /// Shut down cleanly after running for this long, e.g. `5m` or `1h 30m`.
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    max_runtime: Option<Duration>,
+    /// Checks the API, the MQTT broker and the Twitter credentials without
+    /// publishing readings or tweeting, then exits.
+    #[structopt(long)]
+    selftest: bool,
+    /// Prints when the next polls and tweets would happen with this config,
+    /// then exits.
+    #[structopt(long)]
+    print_effective_schedule: bool,
+    /// Logs more: `-v` for debug and `-vv` for trace messages from
+    /// carbon-alert itself, `-vvv` for trace messages from everything. Ignored
+    /// if `RUST_LOG` is set.
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Exits on the first failed poll, MQTT connection error or rejected
+    /// tweet instead of carrying on, see `Config::fail_fast`.
+    #[structopt(long)]
+    strict: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// The log filter for `verbose` occurrences of `-v`.
+fn log_filter(verbose: u8) -> &'static str {
+    match verbose {
+        0 => "info",
+        1 => "info,carbon_alert=debug",
+        2 => "info,carbon_alert=trace",
+        _ => "trace",
+    }
+}
+
+fn parse_region(s: &str) -> Result<RegionId, String> {
+    let id: u16 = s
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid region {:?}: {}", s, e))?;
+    region_from_id(id)
+}
+
+/// Reads a bare `region: 13`, as configs always have, rather than the
+/// `Some(13)` that ron wants for an `Option`.
+fn deserialize_region<'de, D>(deserializer: D) -> Result<Option<RegionId>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+
+    RegionId::deserialize(deserializer).map(Some)
+}
+
+fn region_from_id(id: u16) -> Result<RegionId, String> {
+    use serde::de::IntoDeserializer;
+    use serde::Deserialize;
+
+    let deserializer: serde::de::value::U16Deserializer<serde::de::value::Error> =
+        id.into_deserializer();
+    RegionId::deserialize(deserializer)
+        .map_err(|_| format!("Unknown region {}, expected 1 to 17", id))
+}
+
+#[derive(Debug, structopt::StructOpt)]
+enum Command {
+    /// Publishes historical readings for the primary region to
+    /// `carbon/intensity/history`, oldest first, then exits.
+    Backfill {
+        /// How far back to go.
+        #[structopt(long, default_value = "24")]
+        hours: u32,
+        /// Pause between publishes, so as not to flood the broker.
+        #[structopt(long, default_value = "100")]
+        delay_ms: u64,
+    },
+}
 
 #[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Config {
-    region: RegionId,
+    /// The region to tweet about. Either this or `country` has to be set.
+    #[serde(default, deserialize_with = "deserialize_region")]
+    region: Option<RegionId>,
+    /// A nation to use as the primary region if `region` isn't set.
+    #[serde(default)]
+    country: Option<Country>,
+    /// Picks the region by geolocating this machine's public IP if neither
+    /// `region` nor `country` is set. Falls back to England as a whole if
+    /// that doesn't work out.
+    #[serde(default)]
+    auto_region: bool,
+    /// Geolocation service for `auto_region`. It has to answer with a JSON
+    /// object with the ISO country code as `countryCode` and the postcode as
+    /// `zip`, like ip-api.com does.
+    #[serde(default = "default_geo_endpoint")]
+    geo_endpoint: String,
+    /// Where to find the carbon intensity API, e.g. another instance running
+    /// in `relay_mode`.
+    #[serde(default = "default_api_base_url")]
+    api_base_url: String,
+    /// A mirror of the API to poll while `api_base_url` is down. It doesn't
+    /// get `api_headers`.
+    #[serde(default)]
+    api_fallback_url: Option<String>,
+    /// How often to try `api_base_url` before switching to
+    /// `api_fallback_url`.
+    #[serde(default)]
+    api_failover_retry: RetryPolicy,
+    /// Sent with every request to `api_base_url`, e.g. an API key for a
+    /// gateway in front of it. Values aren't logged.
+    #[serde(default)]
+    api_headers: Option<std::collections::HashMap<String, String>>,
+    /// Serves the API's GET endpoints on `relay_listen`, passing requests on
+    /// to `api_base_url` and caching the responses until the next half hour,
+    /// when the data changes. Point other instances' `api_base_url` here to
+    /// share one set of upstream requests between them.
+    #[serde(default)]
+    relay_mode: bool,
+    #[serde(default = "default_relay_listen")]
+    relay_listen: std::net::SocketAddr,
+    /// Where to serve the latest readings for Prometheus to scrape, at any
+    /// path. Off if unset.
+    #[serde(default)]
+    metrics_listen: Option<std::net::SocketAddr>,
+    /// A StatsD agent, as `host:port`, to send the latest readings and the
+    /// totals from `metrics_listen` to over UDP, with DogStatsD tags. Off if
+    /// unset.
+    #[serde(default)]
+    statsd_addr: Option<String>,
+    /// What the names of the metrics sent to `statsd_addr` start with.
+    #[serde(default = "default_statsd_prefix")]
+    statsd_prefix: String,
+    /// Whether to tweet. The credentials are only needed if so.
+    #[serde(default = "default_true")]
+    twitter_enabled: bool,
+    #[serde(default)]
     twitter_consumer_key: String,
+    #[serde(default)]
     twitter_consumer_secret: String,
+    #[serde(default)]
     twitter_access_token: String,
+    #[serde(default)]
     twitter_access_secret: String,
+    /// Posts to this Bluesky account as well as, or with `twitter_enabled`
+    /// off, instead of tweeting.
+    #[serde(default)]
+    bluesky_handle: Option<String>,
+    /// An app password for `bluesky_handle`, not the account's own.
+    #[serde(default)]
+    bluesky_app_password: String,
+    /// The server hosting `bluesky_handle`.
+    #[serde(default = "default_bluesky_pds_url")]
+    bluesky_pds_url: String,
     mqtt: MQTTConnectionConfig,
+    /// How often to poll the API. The data only changes every half hour, so
+    /// a few minutes is plenty; anything under `MIN_POLL_INTERVAL_SECS` is
+    /// raised to that unless `allow_aggressive_polling` is set.
     poll_interval_secs: u64,
     tweet_interval_secs: u64,
+    /// Allows polling more often than `MIN_POLL_INTERVAL_SECS`, for testing.
+    #[serde(default)]
+    allow_aggressive_polling: bool,
+    /// Delays the first poll by a random number of seconds up to this, so
+    /// that a fleet of instances started together doesn't poll all at once.
+    #[serde(default)]
+    startup_splay_secs: u64,
+    /// Additional regions to poll alongside `region`. These are published to
+    /// MQTT under `carbon/intensity/<regionid>` but aren't tweeted about,
+    /// other than through `compare_regions`.
+    #[serde(default)]
+    regions: Vec<RegionId>,
+    /// Regions from `regions` to compare against `region`. Notifiers post when
+    /// one of them becomes greener by at least `compare_margin`.
+    #[serde(default)]
+    compare_regions: Vec<RegionId>,
+    #[serde(default = "default_compare_margin")]
+    compare_margin: GramsPerKwh,
+    /// Upper bound on concurrent API requests when regions have to be fetched
+    /// one by one.
+    #[serde(default = "default_max_concurrent_requests")]
+    max_concurrent_requests: usize,
+    /// Upper bound on requests to the API per minute, across all regions and
+    /// endpoints. Bursts of up to ten seconds' worth go out straight away,
+    /// after that requests wait their turn.
+    #[serde(default)]
+    api_requests_per_minute: Option<u32>,
+    /// How long an idle connection to the API is kept around for reuse. This
+    /// should comfortably exceed `poll_interval_secs` to avoid a new TLS
+    /// handshake on each poll.
+    #[serde(default = "default_pool_idle_timeout_secs")]
+    pool_idle_timeout_secs: u64,
+    /// How many idle connections to the API to keep, unlimited by default.
+    #[serde(default)]
+    pool_max_idle_per_host: Option<usize>,
+    /// Whether to log a line at info level for every region polled, for
+    /// checking on a deployment without turning on debug logging.
+    #[serde(default)]
+    log_each_poll: bool,
+    /// Whether to poll on a thread and runtime of its own, so that a busy
+    /// runtime can't delay polls.
+    #[serde(default)]
+    dedicated_poll_thread: bool,
+    #[serde(default)]
+    slot_selection: SlotSelection,
+    /// Reports the slot this many half hours after the one `slot_selection`
+    /// picks. The usual endpoints only return a single slot, so with anything
+    /// but 0 the readings come from the 24 hour forecast (`fw24h`) instead.
+    #[serde(default)]
+    slot_offset: usize,
+    /// For this many seconds after a slot boundary, the `current` slot is
+    /// still the one that just ended, so a response that hasn't rolled over
+    /// yet doesn't make the reading flip back and forth.
+    #[serde(default)]
+    boundary_skew_secs: u64,
+    /// Reports the average forecast of this many polls, with the index
+    /// derived from that, to even out the jumps between slots.
+    #[serde(default)]
+    smoothing_window: Option<usize>,
+    /// How many polls to collect before the derived figures mean anything.
+    /// Until then, readings aren't smoothed and rate alerts are held back.
+    #[serde(default)]
+    warmup_samples: usize,
+    /// The most entries any in-memory buffer may hold: the smoothing window
+    /// of each region, the forecasts kept for rate alerts and the relay's
+    /// cache. The oldest ones are dropped beyond that.
+    #[serde(default = "default_max_buffer_entries")]
+    max_buffer_entries: usize,
+    /// Overrides `max_buffer_entries` for single buffers, e.g.
+    /// `(smoothing: Some(48))`.
+    #[serde(default)]
+    buffer_limits: BufferLimits,
+    /// How old entries in the smoothing windows and the relay's cache may
+    /// get before they're dropped. The forecasts kept for rate alerts only
+    /// ever cover `rate_alert_window_secs`.
+    #[serde(default)]
+    max_buffer_age_secs: Option<u64>,
+    /// The figure notifications are about. Defaults to the smoothed forecast
+    /// if there's a `smoothing_window`, and the forecast otherwise.
+    #[serde(default)]
+    notification_source: Option<ValueSource>,
+    /// The figure published over MQTT, with the same default as
+    /// `notification_source`.
+    #[serde(default)]
+    publish_source: Option<ValueSource>,
+    /// Fuels in the generation mix that count towards
+    /// `carbon/intensity/renewables_pct`. Nuclear is added to these for
+    /// `carbon/intensity/low_carbon_pct`.
+    #[serde(default = "default_renewable_fuels")]
+    renewable_fuels: Vec<String>,
+    /// Notifications are held back for this long after startup so readings
+    /// during deploy churn don't cause alerts. MQTT is unaffected.
+    #[serde(default = "default_startup_quiet_secs")]
+    startup_quiet_secs: u64,
+    /// Notify when the forecast rises by at least this many gCO2/kWh within
+    /// `rate_alert_window_secs`, regardless of the index.
+    #[serde(default)]
+    rate_alert_delta: Option<GramsPerKwh>,
+    #[serde(default = "default_rate_alert_window_secs")]
+    rate_alert_window_secs: u64,
+    /// How many consecutive polls a new index has to hold for before it's
+    /// notified about. MQTT always gets the latest reading.
+    #[serde(default = "default_debounce_readings")]
+    debounce_readings: usize,
+    /// Once a notification about an index has gone out, further ones about
+    /// the same index are held back for this long, unless one about another
+    /// index has gone out in between.
+    #[serde(default)]
+    same_condition_cooldown_secs: Option<u64>,
+    /// Only notify about readings at or above this index, e.g.
+    /// `Some("high")`. MQTT is unaffected.
+    #[serde(default)]
+    notify_threshold: Option<Intensity>,
+    /// Whether to notify, once, when the index drops to `recovery_index` or
+    /// below after having been above it, regardless of `notify_threshold`.
+    #[serde(default)]
+    recovery_notify: bool,
+    #[serde(default = "default_recovery_index")]
+    recovery_index: Intensity,
+    /// Replaces `RECOVERED_TEMPLATE`.
+    #[serde(default)]
+    recovery_template: Option<String>,
+    /// Twitter's own `notify_threshold` and `same_condition_cooldown_secs`,
+    /// and which readings it sees.
+    #[serde(default)]
+    twitter: NotifierOverrides,
+    /// Bluesky's own `notify_threshold` and `same_condition_cooldown_secs`,
+    /// and which readings it sees.
+    #[serde(default)]
+    bluesky: NotifierOverrides,
+    /// How to retry failed notifier posts.
+    #[serde(default)]
+    notifier_retry: RetryPolicy,
+    /// Notifications that couldn't be delivered even with retries are
+    /// appended here as JSON lines.
+    #[serde(default)]
+    dead_letter_file: Option<PathBuf>,
+    /// Every reading polled is appended here as a JSON line.
+    #[serde(default)]
+    readings_log_file: Option<PathBuf>,
+    /// Starts a new `readings_log_file` each day, with the date added to its
+    /// name.
+    #[serde(default)]
+    rotate_readings_log: bool,
+    /// Every reading polled is also inserted into this SQLite database,
+    /// which is created if it doesn't exist.
+    #[serde(default)]
+    sqlite_path: Option<PathBuf>,
+    /// Whether to shut down once every notifier task has exited, rather than
+    /// carrying on polling.
+    #[serde(default)]
+    exit_on_no_receivers: bool,
+    /// How often a failed or panicked MQTT or Twitter task is restarted before
+    /// it's given up on.
+    #[serde(default = "default_max_notifier_restarts")]
+    max_notifier_restarts: u32,
+    /// Where operational alerts about this instance itself are posted, as
+    /// JSON with a `text` field.
+    #[serde(default)]
+    alert_webhook_url: Option<String>,
+    /// Whether panics are posted to `alert_webhook_url` with a backtrace.
+    #[serde(default)]
+    report_panics: bool,
+    /// Whether to exit on the first failed poll, MQTT connection error or
+    /// rejected tweet, rather than retrying and carrying on. Meant for CI,
+    /// where a run that only logs its errors looks like it worked. The exit
+    /// code says what failed, see `EXIT_POLL_FAILED` and friends.
+    #[serde(default)]
+    fail_fast: bool,
+    /// Regions to blend into a single figure, each with its weight. The
+    /// weights must add up to 1. When set, the blend is reported in place of
+    /// `region`'s own reading.
+    #[serde(default)]
+    regions_weighted: Vec<(RegionId, f64)>,
+    /// Prefix of the environment variables overriding config values, see
+    /// `apply_env_overrides`.
+    #[serde(default = "default_env_prefix")]
+    env_prefix: String,
+    /// Identifies this instance in MQTT payloads and logs. Defaults to the
+    /// hostname.
+    #[serde(default)]
+    instance_name: Option<String>,
+    /// The hour (UTC) at which to tweet the next 24 hours as a bar of
+    /// coloured squares, one per half-hour slot. Off if unset.
+    #[serde(default)]
+    daily_bar_hour: Option<u32>,
+    /// Whether each day's tweets form a thread, each replying to the one
+    /// before.
+    #[serde(default)]
+    thread_daily: bool,
+    /// Whether tweets compare the reading to the national average over the
+    /// past week.
+    #[serde(default)]
+    compare_weekly_average: bool,
+    /// The intensity at which the greenness score bottoms out at 0. When set,
+    /// tweets include the score and it's published to
+    /// `carbon/intensity/score`.
+    #[serde(default)]
+    score_max: Option<GramsPerKwh>,
+    /// Whether tweets say what the index means for using electricity, and
+    /// it's published to `carbon/intensity/description`.
+    #[serde(default)]
+    describe_index: bool,
+    /// Replaces the built-in description for some indexes, e.g.
+    /// `{"very high": "Put the kettle down"}`.
+    #[serde(default)]
+    descriptions: std::collections::HashMap<Intensity, String>,
+    /// Special treatment for notifications about some indexes, e.g.
+    /// `{"very high": (template: Some("🚨 {region} is {index}!"))}`.
+    #[serde(default)]
+    severity: std::collections::HashMap<Intensity, SeverityOverride>,
+    /// Hashtags for notifications about each index, with or without the
+    /// `#`, e.g. `{"very low": ["LowCarbon"]}`.
+    #[serde(default)]
+    hashtags: std::collections::HashMap<Intensity, Vec<String>>,
+}
+
+/// Entry limits for single buffers, see `Config::buffer_limits`.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BufferLimits {
+    #[serde(default)]
+    smoothing: Option<usize>,
+    #[serde(default)]
+    rate_alert: Option<usize>,
+    #[serde(default)]
+    relay_cache: Option<usize>,
+}
+
+/// How notifications about one index differ from the others, see
+/// `Config::severity`.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SeverityOverride {
+    /// Replaces the template for changes to this index.
+    #[serde(default)]
+    template: Option<String>,
+    /// Hashtags and @-mentions to end tweets about this index with.
+    #[serde(default)]
+    twitter_tags: Vec<String>,
+}
+
+const DEFAULT_API_BASE_URL: &str = "https://api.carbonintensity.org.uk";
+
+fn default_api_base_url() -> String {
+    DEFAULT_API_BASE_URL.to_string()
+}
+
+fn default_relay_listen() -> std::net::SocketAddr {
+    ([0, 0, 0, 0], 8080).into()
+}
+
+fn default_geo_endpoint() -> String {
+    "http://ip-api.com/json/?fields=countryCode,zip".to_string()
+}
+
+fn default_env_prefix() -> String {
+    "CARBON_".to_string()
+}
+
+/// Settings a notifier can have its own of, instead of the global ones of
+/// the same name.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotifierOverrides {
+    #[serde(default)]
+    notify_threshold: Option<Intensity>,
+    #[serde(default)]
+    same_condition_cooldown_secs: Option<u64>,
+    /// Like `mqtt.delivery`. With `Every`, a notifier that's busy posting
+    /// still gets to see each reading, so `debounce_readings` counts polls.
+    #[serde(default)]
+    delivery: Delivery,
+}
+
+/// How often to try a notifier post, and how long to wait in between.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetryPolicy {
+    #[serde(default = "default_retry_attempts")]
+    attempts: u32,
+    /// Wait before the first retry, doubling for each one after.
+    #[serde(default = "default_retry_backoff_secs")]
+    backoff_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: default_retry_attempts(),
+            backoff_secs: default_retry_backoff_secs(),
+        }
+    }
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff_secs() -> u64 {
+    5
+}
+
+fn default_compare_margin() -> GramsPerKwh {
+    GramsPerKwh(50)
+}
+
+fn default_renewable_fuels() -> Vec<String> {
+    ["wind", "solar", "hydro", "biomass"]
+        .iter()
+        .map(|fuel| fuel.to_string())
+        .collect()
+}
+
+fn default_debounce_readings() -> usize {
+    1
+}
+
+fn default_recovery_index() -> Intensity {
+    Intensity::Low
+}
+
+fn default_rate_alert_window_secs() -> u64 {
+    30 * 60
+}
+
+fn default_max_notifier_restarts() -> u32 {
+    5
+}
+
+fn default_statsd_prefix() -> String {
+    "carbon_alert".to_string()
+}
+
+fn default_max_buffer_entries() -> usize {
+    10_000
+}
+
+fn default_bluesky_pds_url() -> String {
+    "https://bsky.social".to_string()
+}
+
+fn default_startup_quiet_secs() -> u64 {
+    60
+}
+
+/// Which of the API's figures to report.
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize)]
+enum ValueSource {
+    #[default]
+    Forecast,
+    /// The moving average of the forecast over `smoothing_window` polls.
+    Smoothed,
+}
+
+/// Which half-hour slot to report when the API returns more than one.
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize)]
+enum SlotSelection {
+    /// Whatever the API lists first.
+    #[default]
+    First,
+    /// The slot containing the current time.
+    Current,
+    /// The slot following the current one.
+    Next,
+}
+
+/// Picks the index of the slot to report out of `spans`, given as `(from, to)`
+/// pairs in chronological order.
+///
+/// If no slot contains `now`, the most recent slot that has already started
+/// stands in for the current one, or the earliest slot if all of them are in
+/// the future. `Next` sticks with the last slot if there is nothing after it.
+fn select_slot(
+    spans: &[(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)],
+    selection: SlotSelection,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<usize> {
+    if spans.is_empty() {
+        return None;
+    }
+    let current = spans
+        .iter()
+        .position(|&(from, to)| from <= now && now < to)
+        .or_else(|| spans.iter().rposition(|&(from, _)| from <= now));
+    Some(match selection {
+        SlotSelection::First => 0,
+        SlotSelection::Current => current.unwrap_or(0),
+        SlotSelection::Next => current.map_or(0, |i| (i + 1).min(spans.len() - 1)),
+    })
+}
+
+/// Which slot to report, see `Config::slot_selection` and
+/// `Config::slot_offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SlotSpec {
+    selection: SlotSelection,
+    offset: usize,
+    skew: chrono::Duration,
+}
+
+/// Picks the index of the slot to report out of `spans`: the one `select_slot`
+/// picks as of `spec.skew` before `now`, moved on by `spec.offset`.
+fn pick_slot(
+    spans: &[(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)],
+    spec: SlotSpec,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<usize, String> {
+    let i = select_slot(spans, spec.selection, now - spec.skew)
+        .ok_or("No forecast slots in response")?;
+    let i = i + spec.offset;
+    if i >= spans.len() {
+        return Err(format!(
+            "Slot offset {} is past the {} slots in the response",
+            spec.offset,
+            spans.len()
+        ));
+    }
+    Ok(i)
+}
+
+fn default_pool_idle_timeout_secs() -> u64 {
+    5 * 60
+}
+
+fn default_max_concurrent_requests() -> usize {
+    4
+}
+
+/// The shortest poll interval that's allowed without
+/// `allow_aggressive_polling`.
+const MIN_POLL_INTERVAL_SECS: u64 = 60;
+
+/// How far the weights in `regions_weighted` may stray from adding up to 1.
+const WEIGHT_TOLERANCE: f64 = 0.001;
+
+impl Config {
+    /// The region to tweet about: `region`, or failing that, `country`.
+    fn primary_region(&self) -> Result<RegionId, String> {
+        self.region
+            .or_else(|| self.country.map(Country::region))
+            .ok_or_else(|| "Neither region nor country is set".to_string())
+    }
+
+    fn slot(&self) -> SlotSpec {
+        SlotSpec {
+            selection: self.slot_selection,
+            offset: self.slot_offset,
+            skew: chrono::Duration::seconds(self.boundary_skew_secs as i64),
+        }
+    }
+
+    fn instance_name(&self) -> String {
+        self.instance_name
+            .clone()
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned())
+    }
+
+    fn topics(&self) -> Topics {
+        Topics {
+            instance: self.mqtt.instance_topics.then(|| self.instance_name()),
+        }
+    }
+
+    /// The primary region followed by any additional ones.
+    fn monitored_regions(&self) -> Result<Vec<RegionId>, String> {
+        Ok(std::iter::once(self.primary_region()?)
+            .chain(self.regions.iter().copied())
+            .collect())
+    }
+
+    /// Replaces the configured regions with `regions`, the first of which
+    /// becomes the primary one. Duplicates are dropped.
+    fn override_regions(&mut self, regions: &[RegionId]) {
+        let mut unique: Vec<RegionId> = Vec::with_capacity(regions.len());
+        for &region in regions {
+            if unique.contains(&region) {
+                log::warn!("Ignoring duplicate region {:?}.", region);
+            } else {
+                unique.push(region);
+            }
+        }
+        if let Some((&first, rest)) = unique.split_first() {
+            self.region = Some(first);
+            self.regions = rest.to_vec();
+        }
+    }
+
+    /// Overrides the credentials and broker address with environment
+    /// variables named after the field path, upper-cased, with dots as
+    /// underscores and prefixed with `prefix`. For example,
+    /// `mqtt.password` is read from `CARBON_MQTT_PASSWORD` by default.
+    fn apply_env_overrides(&mut self, prefix: &str, var: impl Fn(&str) -> Option<String>) {
+        let fields = [
+            ("twitter_consumer_key", &mut self.twitter_consumer_key),
+            ("twitter_consumer_secret", &mut self.twitter_consumer_secret),
+            ("twitter_access_token", &mut self.twitter_access_token),
+            ("twitter_access_secret", &mut self.twitter_access_secret),
+            ("bluesky_app_password", &mut self.bluesky_app_password),
+            ("mqtt.host", &mut self.mqtt.host),
+            ("mqtt.user", &mut self.mqtt.user),
+            ("mqtt.password", &mut self.mqtt.password),
+        ];
+        for (path, field) in fields {
+            let name = format!("{}{}", prefix, path.replace('.', "_").to_uppercase());
+            if let Some(value) = var(&name) {
+                log::debug!("Overriding {} from {}.", path, name);
+                *field = value;
+            }
+        }
+    }
+
+    /// The client for all API requests, which keeps connections alive
+    /// between polls. HTTP/2 is used where the TLS backend negotiates it.
+    fn http_client(&self) -> reqwest::Result<reqwest::Client> {
+        let mut builder = self.http_client_builder();
+        // Invalid headers are left out here and reported by `validate`.
+        if let Ok(headers) = self.api_header_map() {
+            builder = builder.default_headers(headers);
+        }
+        builder.build()
+    }
+
+    /// Like `http_client`, but without `api_headers`, for anything that isn't
+    /// the API. The headers may hold secrets, so they mustn't go elsewhere.
+    fn plain_http_client(&self) -> reqwest::Result<reqwest::Client> {
+        self.http_client_builder().build()
+    }
+
+    fn http_client_builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder()
+            .timeout(HTTP_REQUEST_TIMEOUT)
+            .pool_idle_timeout(Duration::from_secs(self.pool_idle_timeout_secs))
+            .tcp_keepalive(Duration::from_secs(60));
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        builder
+    }
+
+    /// `api_headers`, parsed. Errors name the offending header, but never
+    /// include its value.
+    fn api_header_map(&self) -> Result<reqwest::header::HeaderMap, String> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in self.api_headers.iter().flatten() {
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("api_headers: invalid header name {:?}", name))?;
+            let mut value = reqwest::header::HeaderValue::from_str(value)
+                .map_err(|_| format!("api_headers: invalid value for {}", name))?;
+            // Keeps it out of `Debug` output, and so out of the logs.
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+
+    /// The configured description of `index`, or the built-in one.
+    fn description(&self, index: Intensity) -> &str {
+        self.descriptions
+            .get(&index)
+            .map_or(index.description(), String::as_str)
+    }
+
+    /// The hashtags for `index`, each starting with a `#`, minus any repeats.
+    fn hashtags(&self, index: Intensity) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.hashtags.get(&index).into_iter().flatten() {
+            let tag = format!("#{}", tag.trim_start_matches('#'));
+            if !tags.iter().any(|seen| seen.eq_ignore_ascii_case(&tag)) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
+
+    /// The tags to end a tweet about `index` with: its hashtags, then the
+    /// severity's `twitter_tags`, minus any repeats.
+    fn tweet_tags(&self, index: Intensity) -> Vec<String> {
+        let mut tags = self.hashtags(index);
+        let twitter_tags = self
+            .severity
+            .get(&index)
+            .map_or(&[][..], |severity| &severity.twitter_tags);
+        for tag in twitter_tags {
+            if !tags.iter().any(|seen| seen.eq_ignore_ascii_case(tag)) {
+                tags.push(tag.clone());
+            }
+        }
+        tags
+    }
+
+    /// The most entries a buffer with the given limit of its own may hold.
+    fn buffer_limit(&self, limit: Option<usize>) -> usize {
+        limit.unwrap_or(self.max_buffer_entries)
+    }
+
+    fn max_buffer_age(&self) -> Option<Duration> {
+        self.max_buffer_age_secs.map(Duration::from_secs)
+    }
+
+    /// The figure notifications are about.
+    fn notification_source(&self) -> ValueSource {
+        self.notification_source
+            .unwrap_or_else(|| self.default_source())
+    }
+
+    /// The figure published over MQTT.
+    fn publish_source(&self) -> ValueSource {
+        self.publish_source.unwrap_or_else(|| self.default_source())
+    }
+
+    fn default_source(&self) -> ValueSource {
+        if self.smoothing_window.is_some() {
+            ValueSource::Smoothed
+        } else {
+            ValueSource::Forecast
+        }
+    }
+
+    /// Raises the poll interval to `MIN_POLL_INTERVAL_SECS` if it's below
+    /// that and aggressive polling isn't allowed, with a warning.
+    fn enforce_poll_floor(&mut self) {
+        if self.poll_interval_secs >= MIN_POLL_INTERVAL_SECS {
+            return;
+        }
+        if self.allow_aggressive_polling {
+            log::info!(
+                "Polling every {}s, which is more often than the API updates.",
+                self.poll_interval_secs
+            );
+            return;
+        }
+        log::warn!(
+            "poll_interval_secs of {} is below the minimum of {}, using that instead. The API only updates every 30 minutes; set allow_aggressive_polling to poll faster anyway.",
+            self.poll_interval_secs,
+            MIN_POLL_INTERVAL_SECS
+        );
+        self.poll_interval_secs = MIN_POLL_INTERVAL_SECS;
+    }
+
+    /// Checks constraints that can't be expressed in the types.
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.primary_region()?;
+        if !self.regions_weighted.is_empty() {
+            if let Some(&(region, weight)) = self
+                .regions_weighted
+                .iter()
+                .find(|&&(_, weight)| weight < 0.0 || weight.is_nan())
+            {
+                return Err(format!("Invalid weight {} for region {:?}", weight, region).into());
+            }
+            let total: f64 = self.regions_weighted.iter().map(|&(_, w)| w).sum();
+            if (total - 1.0).abs() > WEIGHT_TOLERANCE {
+                return Err(format!("Region weights add up to {:.3}, not 1", total).into());
+            }
+        }
+        let twitter_credentials = [
+            &self.twitter_consumer_key,
+            &self.twitter_consumer_secret,
+            &self.twitter_access_token,
+            &self.twitter_access_secret,
+        ];
+        if self.twitter_enabled && twitter_credentials.iter().any(|c| c.is_empty()) {
+            return Err(
+                "Twitter credentials are missing, set them or twitter_enabled: false".into(),
+            );
+        }
+        if self.bluesky_handle.is_some() && self.bluesky_app_password.is_empty() {
+            return Err("bluesky_handle is set, but bluesky_app_password isn't".into());
+        }
+        let templates = self.recovery_template.iter().chain(
+            self.severity
+                .values()
+                .filter_map(|severity| severity.template.as_ref()),
+        );
+        for template in templates {
+            if let Some(placeholder) = unknown_placeholder(template) {
+                return Err(format!(
+                    "Unknown placeholder {} in template {:?}",
+                    placeholder, template
+                )
+                .into());
+            }
+        }
+        if self.smoothing_window.is_none()
+            && [self.notification_source, self.publish_source]
+                .contains(&Some(ValueSource::Smoothed))
+        {
+            return Err("Smoothed sources need smoothing_window to be set".into());
+        }
+        if self.api_requests_per_minute == Some(0) {
+            return Err("api_requests_per_minute must be at least 1".into());
+        }
+        self.api_header_map()?;
+        if let Some(region) = self
+            .compare_regions
+            .iter()
+            .find(|region| !self.regions.contains(region))
+        {
+            return Err(format!("compare_regions: {:?} isn't one of the regions", region).into());
+        }
+        for tag in self.hashtags.values().flatten() {
+            if !valid_hashtag(tag.trim_start_matches('#')) {
+                return Err(format!(
+                    "Invalid hashtag {:?}, only letters, digits and _ are allowed, and not only digits",
+                    tag
+                )
+                .into());
+            }
+        }
+        let limits = [
+            ("max_buffer_entries", Some(self.max_buffer_entries)),
+            ("buffer_limits.smoothing", self.buffer_limits.smoothing),
+            ("buffer_limits.rate_alert", self.buffer_limits.rate_alert),
+            ("buffer_limits.relay_cache", self.buffer_limits.relay_cache),
+        ];
+        if let Some((name, _)) = limits.iter().find(|(_, limit)| *limit == Some(0)) {
+            return Err(format!("{} must be at least 1", name).into());
+        }
+        if self.max_buffer_age_secs == Some(0) {
+            return Err("max_buffer_age_secs must be at least 1".into());
+        }
+        if self.report_panics && self.alert_webhook_url.is_none() {
+            return Err("report_panics needs an alert_webhook_url".into());
+        }
+        if self.boundary_skew_secs >= 30 * 60 {
+            return Err("boundary_skew_secs must be less than half an hour".into());
+        }
+        if matches!(self.daily_bar_hour, Some(hour) if hour > 23) {
+            return Err("daily_bar_hour must be between 0 and 23".into());
+        }
+        if self.compare_margin == GramsPerKwh(0) {
+            return Err("compare_margin must be at least 1".into());
+        }
+        if self.score_max == Some(GramsPerKwh(0)) {
+            return Err("score_max must be at least 1".into());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 struct MQTTConnectionConfig {
     host: String,
     port: u16,
     user: String,
     password: String,
+    /// How long to wait for the broker connection, including the TCP and TLS
+    /// handshakes, before giving up and retrying.
+    #[serde(default = "default_connect_timeout_secs")]
+    connect_timeout_secs: u64,
+    /// PEM file with additional CA certificates to trust, e.g. for a broker
+    /// using a private CA.
+    #[serde(default)]
+    ca_cert_path: Option<String>,
+    /// Whether to trust the bundled public roots. Turn this off to only trust
+    /// `ca_cert_path`.
+    #[serde(default = "default_true")]
+    use_bundled_roots: bool,
+    /// Whether to also trust the OS trust store, for roots newer than the
+    /// bundled ones.
+    #[serde(default)]
+    use_native_roots: bool,
+    /// Publishes the forecast as a plain number together with an availability
+    /// topic, and announces both via Home Assistant MQTT discovery.
+    #[serde(default)]
+    home_assistant: Option<HomeAssistantConfig>,
+    /// Decimal places for the forecast on the Home Assistant topic. Without
+    /// this, whole numbers are published as such and fractional ones, like
+    /// blended forecasts, in full.
+    #[serde(default)]
+    forecast_precision: Option<usize>,
+    /// Unit of the forecast, announced via discovery and, over MQTT 5, sent
+    /// as a `unit` user property with each forecast.
+    #[serde(default = "default_forecast_unit")]
+    forecast_unit: String,
+    /// MQTT protocol version, either 3 (3.1.1) or 5. With 5, publishes carry
+    /// a content type and the region and payload schema version as user
+    /// properties.
+    #[serde(default)]
+    mqtt_version: MqttVersion,
+    #[serde(default)]
+    publish_mode: PublishMode,
+    /// Whether to publish every reading, or only the latest one when
+    /// publishing falls behind polling.
+    #[serde(default)]
+    delivery: Delivery,
+    /// Whether to hold back readings whose slot ended more than
+    /// `stale_after_secs` ago, so that retained topics keep the last fresh
+    /// reading rather than an outdated one looking current.
+    #[serde(default)]
+    suppress_stale_publish: bool,
+    #[serde(default = "default_stale_after_secs")]
+    stale_after_secs: u64,
+    /// How long to hold everything back after the broker acknowledges a
+    /// connection, for brokers that drop messages published before the
+    /// session has settled.
+    #[serde(default)]
+    post_connect_delay_ms: u64,
+    /// Payload of `carbon/intensity` and the per-region topics.
+    #[serde(default)]
+    payload_format: PayloadFormat,
+    /// Schema of the JSON payloads. Stay on an older one until all
+    /// subscribers have caught up with the newer one.
+    #[serde(default)]
+    payload_schema: PayloadSchema,
+    /// Whether to publish under `carbon/intensity/<instance_name>/...`
+    /// rather than `carbon/intensity/...`, for several instances sharing a
+    /// broker.
+    #[serde(default)]
+    instance_topics: bool,
+    /// Publishes the primary region's forecast for the next 24 hours as a
+    /// retained JSON array to `forecast_window_topic` on every poll.
+    #[serde(default)]
+    forecast_window: bool,
+    #[serde(default = "default_forecast_window_topic")]
+    forecast_window_topic: String,
+    /// Where the reported index is published as a retained number from 0
+    /// (very low) to 4 (very high), whatever `payload_format` is.
+    #[serde(default = "default_level_topic")]
+    level_topic: String,
+    /// Where the poller's own view of the API is published as a retained
+    /// `PollStatus`, whenever that changes.
+    #[serde(default = "default_poll_status_topic")]
+    poll_status_topic: String,
+    /// Where the state of the broker connection is published as a retained
+    /// `LinkState`, whenever that changes.
+    #[serde(default = "default_link_topic")]
+    link_topic: String,
+}
+
+/// How readings are encoded on the state topics.
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize)]
+enum PayloadFormat {
+    /// The index as a single byte, 0 (very low) to 4 (very high).
+    #[default]
+    Index,
+    /// The whole reading as JSON.
+    Json,
+    /// Seven bytes: the index, the forecast as a big-endian u16 and the
+    /// start of the slot as a big-endian u32 Unix timestamp.
+    Compact,
+}
+
+/// Encodes `reading` for `PayloadFormat::Compact`. Forecasts beyond what fits
+/// in 16 bits are saturated.
+fn compact_payload(reading: &Reading) -> [u8; 7] {
+    let forecast = u16::try_from(reading.intensity.forecast.0).unwrap_or(u16::MAX);
+    let timestamp = u32::try_from(reading.from.timestamp()).unwrap_or(0);
+    let mut payload = [0; 7];
+    payload[0] = reading.intensity.index as u8;
+    payload[1..3].copy_from_slice(&forecast.to_be_bytes());
+    payload[3..].copy_from_slice(&timestamp.to_be_bytes());
+    payload
+}
+
+/// Versions of the JSON payloads, see the README for the differences.
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PayloadSchema {
+    /// Kept as the default so that upgrading doesn't break subscribers.
+    #[default]
+    V1,
+    V2,
+}
+
+impl PayloadSchema {
+    fn version(self) -> u32 {
+        match self {
+            PayloadSchema::V1 => 1,
+            PayloadSchema::V2 => 2,
+        }
+    }
+}
+
+/// How readings are published, see `MQTTConnectionConfig`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Encoding {
+    format: PayloadFormat,
+    schema: PayloadSchema,
+}
+
+/// When to publish readings.
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize)]
+enum PublishMode {
+    /// After every poll.
+    #[default]
+    Always,
+    /// Only when a reading differs from the last one published.
+    OnChange,
+}
+
+/// Which snapshots a notifier gets to see.
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize)]
+enum Delivery {
+    /// Only the latest, skipping any that came in while it was busy.
+    #[default]
+    Latest,
+    /// Every one, in order, as long as it doesn't fall more than
+    /// `EVERY_SNAPSHOT_CAPACITY` behind.
+    Every,
+}
+
+/// Decides which snapshots to publish according to a `PublishMode`.
+#[derive(Debug)]
+struct PublishFilter {
+    mode: PublishMode,
+    /// The reported reading followed by the other regions' readings, as last
+    /// published.
+    last: Option<Vec<IntensityResponse>>,
+}
+
+impl PublishFilter {
+    fn new(mode: PublishMode) -> Self {
+        PublishFilter { mode, last: None }
+    }
+
+    fn should_publish(&mut self, snapshot: &Snapshot) -> bool {
+        if self.mode == PublishMode::Always {
+            return true;
+        }
+        let current: Vec<_> = std::iter::once(snapshot.reported())
+            .chain(&snapshot.others)
+            .map(|r| r.intensity)
+            .collect();
+        if self.last.as_ref() == Some(&current) {
+            return false;
+        }
+        self.last = Some(current);
+        true
+    }
 }
 
-#[derive(Debug, Copy, Clone, serde::Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde_repr::Deserialize_repr)]
+#[repr(u8)]
+enum MqttVersion {
+    #[default]
+    V3 = 3,
+    V5 = 5,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct HomeAssistantConfig {
+    #[serde(default = "default_discovery_prefix")]
+    discovery_prefix: String,
+    /// Note that Home Assistant expects `ppm` for `carbon_dioxide`, so it will
+    /// warn about the gCO2/kWh unit with that class.
+    #[serde(default)]
+    device_class: Option<String>,
+}
+
+fn default_stale_after_secs() -> u64 {
+    60 * 60
+}
+
+fn default_level_topic() -> String {
+    LEVEL_TOPIC.to_string()
+}
+
+fn default_link_topic() -> String {
+    LINK_TOPIC.to_string()
+}
+
+fn default_poll_status_topic() -> String {
+    POLL_STATUS_TOPIC.to_string()
+}
+
+fn default_forecast_window_topic() -> String {
+    FORECAST_WINDOW_TOPIC.to_string()
+}
+
+fn default_forecast_unit() -> String {
+    FORECAST_UNIT.to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl MQTTConnectionConfig {
+    fn encoding(&self) -> Encoding {
+        Encoding {
+            format: self.payload_format,
+            schema: self.payload_schema,
+        }
+    }
+
+    fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.stale_after_secs)
+    }
+
+    /// Sets up a client and its event loop. Nothing happens on the network
+    /// until the event loop is polled.
+    fn client(
+        &self,
+        client_id: &str,
+        last_will: Option<Message>,
+        region: RegionId,
+        instance: &str,
+    ) -> Result<(MqttClient, MqttEventLoop), Box<dyn std::error::Error>> {
+        let transport = rumqttc::Transport::tls_with_config(self.tls_config()?.into());
+        match self.mqtt_version {
+            MqttVersion::V3 => {
+                let mut options = rumqttc::MqttOptions::new(client_id, &self.host, self.port);
+                options
+                    .set_keep_alive(Duration::from_secs(5))
+                    .set_credentials(&self.user, &self.password)
+                    .set_transport(transport);
+                if let Some(will) = last_will {
+                    options.set_last_will(rumqttc::LastWill::new(
+                        will.topic,
+                        will.payload,
+                        rumqttc::QoS::AtLeastOnce,
+                        will.retain,
+                    ));
+                }
+                let (client, mut event_loop) = rumqttc::AsyncClient::new(options, 10);
+                let mut network_options = rumqttc::NetworkOptions::new();
+                network_options.set_connection_timeout(self.connect_timeout_secs);
+                event_loop.set_network_options(network_options);
+                Ok((
+                    MqttClient::V3(client),
+                    MqttEventLoop::V3(Box::new(event_loop)),
+                ))
+            }
+            MqttVersion::V5 => {
+                let mut options = rumqttc::v5::MqttOptions::new(client_id, &self.host, self.port);
+                options
+                    .set_keep_alive(Duration::from_secs(5))
+                    .set_connection_timeout(self.connect_timeout_secs)
+                    .set_credentials(&self.user, &self.password)
+                    .set_transport(transport);
+                if let Some(will) = last_will {
+                    options.set_last_will(rumqttc::v5::mqttbytes::v5::LastWill::new(
+                        will.topic,
+                        will.payload,
+                        rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                        will.retain,
+                        None,
+                    ));
+                }
+                let (client, event_loop) = rumqttc::v5::AsyncClient::new(options, 10);
+                let user_properties = vec![
+                    ("region".to_string(), (region as u16).to_string()),
+                    ("instance".to_string(), instance.to_string()),
+                    (
+                        "schema_version".to_string(),
+                        self.payload_schema.version().to_string(),
+                    ),
+                ];
+                Ok((
+                    MqttClient::V5 {
+                        client,
+                        user_properties,
+                    },
+                    MqttEventLoop::V5(Box::new(event_loop)),
+                ))
+            }
+        }
+    }
+
+    fn tls_config(
+        &self,
+    ) -> Result<rumqttc::tokio_rustls::rustls::ClientConfig, Box<dyn std::error::Error>> {
+        use rumqttc::tokio_rustls::rustls;
+
+        let mut roots = rustls::RootCertStore::empty();
+        let mut bundled = 0;
+        if self.use_bundled_roots {
+            bundled = webpki_roots_rumqttc::TLS_SERVER_ROOTS.len();
+            roots.add_trust_anchors(webpki_roots_rumqttc::TLS_SERVER_ROOTS.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+        let mut native = 0;
+        if self.use_native_roots {
+            // The other roots may still do, so this isn't fatal.
+            match rustls_native_certs::load_native_certs() {
+                Ok(certs) => {
+                    let certs: Vec<_> = certs.into_iter().map(|cert| cert.0).collect();
+                    let (valid, invalid) = roots.add_parsable_certificates(&certs);
+                    native = valid;
+                    if invalid > 0 {
+                        log::debug!("Skipped {} unusable certificates from the OS.", invalid);
+                    }
+                }
+                Err(e) => log::warn!("Failed to load the OS trust store: {}", e),
+            }
+        }
+        log::info!(
+            "Loaded {} bundled and {} OS root certificates for MQTT.",
+            bundled,
+            native
+        );
+        if let Some(path) = &self.ca_cert_path {
+            let file = std::fs::File::open(path)
+                .map_err(|e| format!("Failed to open MQTT CA certificate {}: {}", path, e))?;
+            let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(file))
+                .map_err(|e| format!("MQTT CA certificate {} is not valid PEM: {}", path, e))?;
+            let (valid, invalid) = roots.add_parsable_certificates(&certs);
+            if valid == 0 {
+                return Err(format!("No usable certificates in MQTT CA file {}", path).into());
+            }
+            if invalid > 0 {
+                log::warn!("Skipped {} invalid certificates in {}.", invalid, path);
+            }
+        }
+        if roots.is_empty() {
+            return Err(
+                "No MQTT root certificates: set ca_cert_path, use_bundled_roots or use_native_roots"
+                    .into(),
+            );
+        }
+        Ok(rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth())
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
 #[repr(u8)]
 enum Intensity {
     VeryLow = 0,
@@ -31,6 +1322,52 @@ enum Intensity {
     VeryHigh = 4,
 }
 
+impl Intensity {
+    /// The index the API would assign to `forecast` gCO2/kWh, using its
+    /// regional bands for 2021 onwards.
+    fn from_forecast(forecast: GramsPerKwh) -> Self {
+        match forecast.0 {
+            0..=49 => Intensity::VeryLow,
+            50..=129 => Intensity::Low,
+            130..=209 => Intensity::Moderate,
+            210..=310 => Intensity::High,
+            _ => Intensity::VeryHigh,
+        }
+    }
+
+    /// A coloured square for the index, as used in `emoji_bar`.
+    fn emoji(self) -> &'static str {
+        match self {
+            Intensity::VeryLow | Intensity::Low => "🟩",
+            Intensity::Moderate => "🟨",
+            Intensity::High => "🟧",
+            Intensity::VeryHigh => "🟥",
+        }
+    }
+
+    /// The index as the API spells it.
+    fn label(self) -> &'static str {
+        match self {
+            Intensity::VeryLow => "very low",
+            Intensity::Low => "low",
+            Intensity::Moderate => "moderate",
+            Intensity::High => "high",
+            Intensity::VeryHigh => "very high",
+        }
+    }
+
+    /// What to do about it, for people who don't know what the index means.
+    fn description(self) -> &'static str {
+        match self {
+            Intensity::VeryLow => "Great time to use electricity",
+            Intensity::Low => "Good time to use electricity",
+            Intensity::Moderate => "Fine to use electricity, but it may get greener",
+            Intensity::High => "Try to wait if you can",
+            Intensity::VeryHigh => "Avoid using electricity if you can",
+        }
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for Intensity {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -51,7 +1388,20 @@ impl<'de> serde::Deserialize<'de> for Intensity {
     }
 }
 
-#[derive(serde_repr::Serialize_repr, serde_repr::Deserialize_repr, PartialEq, Debug, Clone)]
+#[derive(
+    serde_repr::Serialize_repr,
+    serde_repr::Deserialize_repr,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    Clone,
+    Copy,
+)]
+/// The API's regions. 1 to 14 are the grid supply point groups, such as
+/// London (13), which together cover Great Britain. 15 to 17 are whole
+/// nations, each made up of several of those, so England (15) includes
+/// London (13).
 #[repr(u16)]
 enum RegionId {
     NorthScotland = 1,
@@ -73,7 +1423,46 @@ enum RegionId {
     Wales = 17,
 }
 
-#[derive(Debug, serde::Deserialize)]
+/// The nations with their own endpoints in the API.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+enum Country {
+    England,
+    Scotland,
+    Wales,
+}
+
+impl Country {
+    fn region(self) -> RegionId {
+        match self {
+            Country::England => RegionId::England,
+            Country::Scotland => RegionId::Scotland,
+            Country::Wales => RegionId::Wales,
+        }
+    }
+}
+
+/// The URL of the endpoint for `region` alone. The nations have dedicated
+/// endpoints, which respond just like the numbered ones.
+fn region_url(base_url: &str, region: RegionId) -> String {
+    match region {
+        RegionId::England => format!("{}/regional/england", base_url),
+        RegionId::Scotland => format!("{}/regional/scotland", base_url),
+        RegionId::Wales => format!("{}/regional/wales", base_url),
+        region => format!("{}/regional/regionid/{}", base_url, region as u16),
+    }
+}
+
+/// The URL of the endpoint for `region` over `range`, e.g. `{from}/fw24h`.
+/// The nations don't have dedicated endpoints for time ranges, so all
+/// regions go by their ID here.
+fn region_range_url(base_url: &str, region: RegionId, range: &str) -> String {
+    format!(
+        "{}/regional/intensity/{}/regionid/{}",
+        base_url, range, region as u16
+    )
+}
+
+#[derive(Debug, serde::Deserialize)]
 struct ErrorResponse {
     code: String,
     message: String,
@@ -81,17 +1470,83 @@ struct ErrorResponse {
 
 #[derive(Debug, serde::Deserialize)]
 struct DataItemResponse {
+    /// Only used to look up the region for a postcode.
+    #[serde(default)]
+    regionid: Option<u16>,
     shortname: String,
     data: Vec<ForecastResponse>,
 }
 
+/// Response of the `/regional` endpoint, which covers every region at once.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BulkRegionalResponse {
+    Data(Vec<BulkForecastResponse>),
+    Error(ErrorResponse),
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BulkForecastResponse {
+    #[serde(with = "carbon_date_format")]
+    from: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "carbon_date_format")]
+    to: chrono::DateTime<chrono::Utc>,
+    regions: Vec<BulkRegionResponse>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BulkRegionResponse {
+    // Not a `RegionId`, as the bulk response also includes an aggregate
+    // region 18 for the whole of GB.
+    regionid: u16,
+    shortname: String,
+    intensity: IntensityResponse,
+    #[serde(default)]
+    generationmix: Vec<FuelShare>,
+}
+
 #[derive(Debug, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
-enum RegionalResponse {
+pub(crate) enum RegionalResponse {
     Data(Vec<DataItemResponse>),
     Error(ErrorResponse),
 }
 
+/// Response of the `/regional/intensity/{from}/{to}/regionid/{id}` endpoint,
+/// which unlike the others doesn't wrap the region in an array.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RegionalHistoryResponse {
+    Data(DataItemResponse),
+    Error(ErrorResponse),
+}
+
+/// Response of the `/intensity/stats/{from}/{to}` endpoint and its
+/// block-averaged variant. Unlike the others, these are national only.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StatsResponse {
+    Data(Vec<StatsItemResponse>),
+    Error(ErrorResponse),
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StatsItemResponse {
+    #[serde(with = "carbon_date_format")]
+    from: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "carbon_date_format")]
+    to: chrono::DateTime<chrono::Utc>,
+    intensity: IntensityStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+struct IntensityStats {
+    max: GramsPerKwh,
+    average: GramsPerKwh,
+    min: GramsPerKwh,
+    index: Intensity,
+}
+
 #[derive(Debug, serde::Deserialize)]
 struct ForecastResponse {
     #[serde(with = "carbon_date_format")]
@@ -99,13 +1554,40 @@ struct ForecastResponse {
     #[serde(with = "carbon_date_format")]
     to: chrono::DateTime<chrono::Utc>,
     intensity: IntensityResponse,
+    #[serde(default)]
+    generationmix: Vec<FuelShare>,
+}
+
+/// How much of the generation in a slot comes from `fuel`, in percent.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct FuelShare {
+    fuel: String,
+    perc: f64,
+}
+
+/// The part of `mix` generated from `fuels`, in percent.
+fn fuel_share(mix: &[FuelShare], fuels: &[String]) -> f64 {
+    mix.iter()
+        .filter(|share| fuels.contains(&share.fuel))
+        .map(|share| share.perc)
+        .sum()
+}
+
+/// The part of `mix` generated from `renewables` or nuclear, in percent.
+fn low_carbon_share(mix: &[FuelShare], renewables: &[String]) -> f64 {
+    let nuclear: f64 = mix
+        .iter()
+        .filter(|share| share.fuel == "nuclear")
+        .map(|share| share.perc)
+        .sum();
+    fuel_share(mix, renewables) + nuclear
 }
 
 mod carbon_date_format {
     use chrono::TimeZone;
     use serde::Deserialize;
 
-    const FORMAT: &str = "%Y-%m-%dT%H:%MZ";
+    pub const FORMAT: &str = "%Y-%m-%dT%H:%MZ";
 
     pub fn serialize<S>(
         date: &chrono::DateTime<chrono::Utc>,
@@ -129,243 +1611,7683 @@ mod carbon_date_format {
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy)]
+/// A carbon intensity, kept apart from other numbers such as durations or
+/// percentages so that they can't be mixed up.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+struct GramsPerKwh(u32);
+
+impl GramsPerKwh {
+    /// How far this is above `other`, or zero if it isn't.
+    fn saturating_sub(self, other: GramsPerKwh) -> GramsPerKwh {
+        GramsPerKwh(self.0.saturating_sub(other.0))
+    }
+
+    /// The nearest whole value to `value`.
+    fn round(value: f64) -> GramsPerKwh {
+        GramsPerKwh(value.round() as u32)
+    }
+}
+
+impl From<GramsPerKwh> for f64 {
+    fn from(value: GramsPerKwh) -> f64 {
+        value.0.into()
+    }
+}
+
+impl std::fmt::Display for GramsPerKwh {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.0, FORECAST_UNIT)
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(from = "RawIntensityResponse")]
 struct IntensityResponse {
     index: Intensity,
-    forecast: u32,
+    forecast: GramsPerKwh,
+    /// The measured intensity, which only the national endpoints report, and
+    /// only for slots that have passed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    actual: Option<GramsPerKwh>,
+    /// The range the forecast is expected to fall within, where the response
+    /// gives one. Only meaningful together, see `forecast_range`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forecast_low: Option<GramsPerKwh>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forecast_high: Option<GramsPerKwh>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    pretty_env_logger::init();
-    log::info!("Starting up.");
-    let config = if let Some(path) = std::env::args().collect::<Vec<_>>().get(1) {
-        let config_str = tokio::fs::read_to_string(path).await?;
-        ron::de::from_str::<Config>(&config_str)?
-    } else {
-        log::error!("ERR: Missing configuration argument.");
-        std::process::exit(1);
-    };
-    log::trace!("Parsed config: {:?}", config);
-    let (tx, rx) = tokio::sync::watch::channel::<Option<IntensityResponse>>(None);
+/// `IntensityResponse` as the API sends it, which now and then has a null or
+/// missing index.
+#[derive(serde::Deserialize)]
+struct RawIntensityResponse {
+    #[serde(default)]
+    index: Option<Intensity>,
+    forecast: GramsPerKwh,
+    #[serde(default)]
+    actual: Option<GramsPerKwh>,
+    #[serde(default)]
+    forecast_low: Option<GramsPerKwh>,
+    #[serde(default)]
+    forecast_high: Option<GramsPerKwh>,
+}
 
-    let mqtt_handle = tokio::task::spawn(run_mqtt(config.clone(), rx.clone()));
-    let tweet_handle = tokio::task::spawn(run_tweeter(config.clone(), rx));
-    log::trace!("Set up handles.");
+impl From<RawIntensityResponse> for IntensityResponse {
+    fn from(raw: RawIntensityResponse) -> Self {
+        let index = raw.index.unwrap_or_else(|| {
+            let index = Intensity::from_forecast(raw.forecast);
+            log::info!(
+                "No index for a forecast of {}, derived {:?}.",
+                raw.forecast,
+                index
+            );
+            index
+        });
+        IntensityResponse {
+            index,
+            forecast: raw.forecast,
+            actual: raw.actual,
+            forecast_low: raw.forecast_low,
+            forecast_high: raw.forecast_high,
+        }
+    }
+}
 
-    let stream = poll_api(config.clone());
-    futures_util::pin_mut!(stream);
-    log::debug!("Polling API stream.");
-    while let Some(n) = stream.next().await {
-        log::debug!("Received new data: {:?}", &n);
-        tx.send(n.ok())?;
+impl IntensityResponse {
+    fn forecast_range(&self) -> Option<(GramsPerKwh, GramsPerKwh)> {
+        self.forecast_low.zip(self.forecast_high)
+    }
+
+    /// Replaces the forecast with a figure that the range doesn't apply to.
+    fn replace_forecast(&mut self, forecast: GramsPerKwh) {
+        self.forecast = forecast;
+        self.forecast_low = None;
+        self.forecast_high = None;
     }
-    let _ = tokio::join!(mqtt_handle, tweet_handle);
-    Ok(())
 }
 
-fn poll_api(
-    config: Config,
-) -> impl futures_core::Stream<Item = Result<IntensityResponse, Box<dyn std::error::Error>>> {
-    let url = format!(
-        "https://api.carbonintensity.org.uk/regional/regionid/{}",
-        config.clone().region as u16
-    );
-    async_stream::try_stream! {
-        loop {
-            let resp: RegionalResponse = reqwest::get(&url).await?.json().await?;
-            let intensity = match resp {
-                RegionalResponse::Data(d) => d[0].data[0].intensity,
-                RegionalResponse::Error(e) => {
-                    panic!("Error: {}", e.message);
+/// `readings` as reported with `source`, given their `smoothed` versions if
+/// there's a `Smoother`.
+fn sourced(
+    readings: &[Reading],
+    smoothed: Option<&[Reading]>,
+    source: ValueSource,
+) -> Vec<Reading> {
+    match (source, smoothed) {
+        (ValueSource::Smoothed, Some(smoothed)) => smoothed.to_vec(),
+        _ => readings.to_vec(),
+    }
+}
 
-                }
-            };
-            yield intensity;
-            tokio::time::sleep(std::time::Duration::from_secs(config.poll_interval_secs)).await;
+/// Replaces forecasts with their moving average per region.
+struct Smoother {
+    window: usize,
+    /// The latest forecasts per region, with the start of their slot.
+    recent: std::collections::HashMap<
+        RegionId,
+        std::collections::VecDeque<(chrono::DateTime<chrono::Utc>, GramsPerKwh)>,
+    >,
+    /// How much older than the latest reading's slot the others' may be.
+    max_age: Option<Duration>,
+    /// Readings per region that are passed through as they are.
+    warmup: usize,
+    /// Readings seen per region, counted up to `warmup`.
+    seen: std::collections::HashMap<RegionId, usize>,
+    size: BufferSize,
+}
+
+impl Smoother {
+    fn new(window: usize) -> Self {
+        Smoother {
+            window: window.max(1),
+            recent: std::collections::HashMap::new(),
+            max_age: None,
+            warmup: 0,
+            seen: std::collections::HashMap::new(),
+            size: BufferSize::default(),
         }
     }
-}
 
-async fn run_mqtt(
-    config: Config,
-    mut intensity_rx: tokio::sync::watch::Receiver<Option<IntensityResponse>>,
-) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
-    let mut client_config = rumqttc::ClientConfig::new();
-    client_config
-        .root_store
-        .add_server_trust_anchors(&webpki_roots_rumqttc::TLS_SERVER_ROOTS);
-
-    let mut mqttoptions = rumqttc::MqttOptions::new("mqtt", config.mqtt.host, config.mqtt.port);
-    mqttoptions
-        .set_keep_alive(Duration::from_secs(5))
-        .set_credentials(config.mqtt.user, config.mqtt.password)
-        .set_transport(rumqttc::Transport::tls_with_config(client_config.into()));
-
-    let (client, mut event_loop) = rumqttc::AsyncClient::new(mqttoptions, 10);
-    client
-        .subscribe("carbon/intensity", rumqttc::QoS::AtMostOnce)
-        .await
-        .unwrap();
-    log::info!("Connected to MQTT broker.");
-    tokio::task::spawn(async move {
-        loop {
-            let event = event_loop.poll().await;
-            if let Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))) = event {
-                log::info!("Publish event: {:?}", publish);
+    /// Accounts for the buffered forecasts in `size`.
+    fn with_buffer_size(mut self, size: BufferSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    fn with_warmup(mut self, samples: usize) -> Self {
+        self.warmup = samples;
+        self
+    }
+
+    fn with_max_entries(mut self, max: usize) -> Self {
+        if self.window > max {
+            log::warn!(
+                "smoothing_window of {} is over the smoothing buffer's limit, using {} instead.",
+                self.window,
+                max
+            );
+            self.window = max.max(1);
+        }
+        self
+    }
+
+    fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Smooths `reading`, keeping the original forecast as `raw_forecast`.
+    /// During the warmup, it's only recorded.
+    fn apply(&mut self, reading: &mut Reading) {
+        let recent = self.recent.entry(reading.region).or_default();
+        let before = recent.len();
+        recent.push_back((reading.from, reading.intensity.forecast));
+        if recent.len() > self.window {
+            recent.pop_front();
+        }
+        if let Some(max_age) = self
+            .max_age
+            .and_then(|age| chrono::Duration::from_std(age).ok())
+        {
+            let cutoff = reading.from - max_age;
+            while matches!(recent.front(), Some(&(from, _)) if from < cutoff) {
+                recent.pop_front();
             }
         }
-    });
-    while intensity_rx.changed().await.is_ok() {
-        let res = *intensity_rx.borrow();
-        if let Some(intensity) = res {
-            log::info!("Publishing: {:?}", intensity);
-            client
-                .publish(
-                    "carbon/intensity",
-                    rumqttc::QoS::AtLeastOnce,
-                    false,
-                    [intensity.index as u8],
-                )
-                .await
-                // HACK: Properly handle errors
-                .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        self.size.resize(before, recent.len());
+        let seen = self.seen.entry(reading.region).or_default();
+        if *seen < self.warmup {
+            *seen += 1;
+            return;
         }
+        let total: u64 = recent.iter().map(|(_, f)| u64::from(f.0)).sum();
+        let smoothed = GramsPerKwh::round(total as f64 / recent.len() as f64);
+        reading.raw_forecast = Some(reading.intensity.forecast);
+        reading.intensity.replace_forecast(smoothed);
+        reading.intensity.index = Intensity::from_forecast(smoothed);
     }
+}
 
-    Ok(())
+impl Drop for Smoother {
+    fn drop(&mut self) {
+        let entries = self.recent.values().map(|recent| recent.len()).sum();
+        self.size.resize(entries, 0);
+    }
 }
 
-async fn run_tweeter(
-    config: Config,
-    mut intensity_rx: tokio::sync::watch::Receiver<Option<IntensityResponse>>,
-) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
+/// The intensity of a single region for a single half-hour slot.
+#[derive(Debug, Clone, serde::Serialize)]
+struct Reading {
+    region: RegionId,
+    shortname: String,
+    #[serde(with = "carbon_date_format")]
+    from: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "carbon_date_format")]
+    to: chrono::DateTime<chrono::Utc>,
+    intensity: IntensityResponse,
+    /// Whether the slot's value is an estimate rather than a measurement. The
+    /// API marks measured slots by reporting an actual value; everything
+    /// else, including the slot in progress, is estimated.
+    is_estimated: bool,
+    /// The forecast before smoothing, if `smoothing_window` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_forecast: Option<GramsPerKwh>,
+    /// Published on topics of its own, so it's left out of the reading's
+    /// payload.
+    #[serde(skip)]
+    generation_mix: Vec<FuelShare>,
+}
+
+/// Everything learned from a single poll of the API.
+#[derive(Debug, Clone)]
+struct Snapshot {
+    /// The reading for the configured `region`.
+    primary: Reading,
+    /// Readings for the additional `regions`, in config order.
+    others: Vec<Reading>,
+    /// The blend of `regions_weighted`, if configured.
+    blended: Option<Blend>,
+    /// The primary region's forecast for the next 24 hours, if enabled and
+    /// fetched successfully.
+    window: Option<Vec<Reading>>,
+    /// What `reported` would be with `notification_source`, if that differs
+    /// from `publish_source`.
+    notified: Option<Reading>,
+    /// When the poll that this came from finished.
+    polled_at: chrono::DateTime<chrono::Utc>,
+    /// Counts snapshots from 1, so that notifiers can tell when they missed
+    /// some.
+    seq: u64,
+}
+
+impl Snapshot {
+    /// The reading to notify about and publish as the main state: the blend
+    /// if there is one, the primary region's reading otherwise.
+    fn reported(&self) -> &Reading {
+        self.blended.as_ref().map_or(&self.primary, |b| &b.reading)
+    }
+
+    /// Whole seconds from the poll this came from to `now`, i.e. since the
+    /// last successful one if this is the latest snapshot.
+    fn staleness_secs(&self, now: chrono::DateTime<chrono::Utc>) -> i64 {
+        (now - self.polled_at).num_seconds().max(0)
+    }
+
+    /// Whether the reported reading's slot ended more than `after` before
+    /// `now`.
+    fn is_stale(&self, now: chrono::DateTime<chrono::Utc>, after: Duration) -> bool {
+        let after =
+            chrono::Duration::from_std(after).unwrap_or_else(|_| chrono::Duration::max_value());
+        now.signed_duration_since(self.reported().to) > after
+    }
+
+    /// The reading to notify about, which may be based on another figure than
+    /// `reported`.
+    fn notified(&self) -> &Reading {
+        self.notified.as_ref().unwrap_or_else(|| self.reported())
+    }
+
+    /// The forecast of `reported`, without rounding in the case of a blend.
+    fn reported_forecast(&self) -> f64 {
+        self.blended
+            .as_ref()
+            .map_or(self.primary.intensity.forecast.into(), |b| b.forecast)
+    }
+}
+
+/// A weighted average of several regions.
+#[derive(Debug, Clone)]
+struct Blend {
+    /// The blend with its forecast rounded to fit a `Reading`.
+    reading: Reading,
+    forecast: f64,
+}
+
+/// Combines `readings` into a weighted average according to `weights`. The
+/// blend takes the slot and region of its most heavily weighted part, and
+/// an index derived from the blended forecast. It's estimated if any of its
+/// parts are.
+fn blend(weights: &[(RegionId, f64)], readings: &[Reading]) -> Result<Blend, String> {
+    let mut forecast = 0.0;
+    let mut names = Vec::with_capacity(weights.len());
+    let mut heaviest: Option<(&Reading, f64)> = None;
+    let mut is_estimated = false;
+    for &(region, weight) in weights {
+        let reading = readings
+            .iter()
+            .find(|r| r.region == region)
+            .ok_or_else(|| format!("No reading for weighted region {:?}", region))?;
+        forecast += f64::from(reading.intensity.forecast) * weight;
+        names.push(reading.shortname.as_str());
+        is_estimated |= reading.is_estimated;
+        if !matches!(heaviest, Some((_, w)) if w >= weight) {
+            heaviest = Some((reading, weight));
+        }
+    }
+    let (base, _) = heaviest.ok_or("No regions to blend")?;
+    let rounded = GramsPerKwh::round(forecast);
+    Ok(Blend {
+        reading: Reading {
+            shortname: names.join(" / "),
+            intensity: IntensityResponse {
+                index: Intensity::from_forecast(rounded),
+                forecast: rounded,
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            },
+            is_estimated,
+            raw_forecast: None,
+            // There's no telling how the regions' mixes would add up.
+            generation_mix: Vec::new(),
+            ..base.clone()
+        },
+        forecast,
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    use structopt::StructOpt;
+
+    let opt = Opt::from_args();
+    let mut logger = pretty_env_logger::formatted_builder();
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => logger.parse_filters(&filters),
+        Err(_) => logger.parse_filters(log_filter(opt.verbose)),
+    };
+    logger.init();
+    log::info!("Starting up.");
+    let config_str = read_config(&opt.config, opt.config_read_retries).await?;
+    let mut config = ron::de::from_str::<Config>(&config_str)
+        .map_err(|e| format!("Invalid config {}: {}", config_source(&opt.config), e))?;
+    let env_prefix = opt.env_prefix.unwrap_or_else(|| config.env_prefix.clone());
+    config.apply_env_overrides(&env_prefix, |name| std::env::var(name).ok());
+    config.override_regions(&opt.regions);
+    config.fail_fast |= opt.strict;
+    if config.fail_fast {
+        config.notifier_retry.attempts = 1;
+        config.max_notifier_restarts = 0;
+    }
+    let shared = std::sync::Arc::new(Shared::default());
+    if config.region.is_none() && config.country.is_none() && config.auto_region {
+        config.region = Some(
+            detect_region(
+                &config.http_client()?,
+                &shared.api_rate_limit,
+                &config.plain_http_client()?,
+                &config.api_base_url,
+                &config.geo_endpoint,
+            )
+            .await,
+        );
+    }
+    config.validate()?;
+    config.enforce_poll_floor();
+    if let Some(per_minute) = config.api_requests_per_minute {
+        shared.api_rate_limit.set_limit(per_minute);
+    }
+    config.mqtt.tls_config()?;
+    if config.report_panics {
+        if let Some(url) = &config.alert_webhook_url {
+            install_panic_hook(url.clone(), config.instance_name());
+        }
+    }
+
+    if opt.selftest {
+        return selftest(&config, &shared).await;
+    }
+    if opt.print_effective_schedule {
+        print!("{}", schedule_text(&config, chrono::Utc::now()));
+        return Ok(());
+    }
+    if let Some(Command::Backfill { hours, delay_ms }) = opt.command {
+        return backfill(&config, &shared, hours, Duration::from_millis(delay_ms)).await;
+    }
+    let (tx, rx) = SnapshotTx::new();
+
+    let _relay = config.relay_mode.then(|| {
+        let (config, shared) = (config.clone(), shared.clone());
+        AbortOnDrop(tokio::spawn(async move {
+            if let Err(e) = run_relay(&config, shared).await {
+                log::error!("The relay has stopped: {:#}", e);
+            }
+        }))
+    });
+    let mut notifiers = tokio::task::JoinSet::new();
+    notifiers.spawn(supervise_notifier("MQTT", config.max_notifier_restarts, {
+        let (config, rx) = (config.clone(), rx.clone());
+        let snapshots = tx.subscribe(config.mqtt.delivery);
+        move || run_mqtt(config.clone(), rx.clone(), snapshots.resubscribe())
+    }));
+    if config.twitter_enabled {
+        notifiers.spawn(supervise_notifier(
+            "Twitter",
+            config.max_notifier_restarts,
+            {
+                let (config, shared) = (config.clone(), shared.clone());
+                let snapshots = tx.subscribe(config.twitter.delivery);
+                move || run_tweeter(config.clone(), snapshots.resubscribe(), shared.clone())
+            },
+        ));
+    }
+    if config.twitter_enabled && config.daily_bar_hour.is_some() {
+        notifiers.spawn(supervise_notifier(
+            "Daily bar",
+            config.max_notifier_restarts,
+            {
+                let (config, rx, shared) = (config.clone(), rx.clone(), shared.clone());
+                move || run_daily_bar(config.clone(), rx.clone(), shared.clone())
+            },
+        ));
+    }
+    if config.metrics_listen.is_some() {
+        notifiers.spawn(supervise_notifier(
+            "Metrics",
+            config.max_notifier_restarts,
+            {
+                let (config, rx, shared) = (config.clone(), rx.clone(), shared.clone());
+                move || run_metrics(config.clone(), rx.clone(), shared.clone())
+            },
+        ));
+    }
+    if config.statsd_addr.is_some() {
+        notifiers.spawn(supervise_notifier(
+            "StatsD",
+            config.max_notifier_restarts,
+            {
+                let (config, rx, shared) = (config.clone(), rx.clone(), shared.clone());
+                move || run_statsd(config.clone(), rx.clone(), shared.clone())
+            },
+        ));
+    }
+    if config.bluesky_handle.is_some() {
+        notifiers.spawn(supervise_notifier(
+            "Bluesky",
+            config.max_notifier_restarts,
+            {
+                let (config, shared) = (config.clone(), shared.clone());
+                let snapshots = tx.subscribe(config.bluesky.delivery);
+                move || run_bluesky(config.clone(), snapshots.resubscribe(), shared.clone())
+            },
+        ));
+    }
+    // Otherwise the channel would never close once the notifiers are gone.
+    drop(rx);
+    log::trace!("Set up handles.");
+
+    let mut stream = if config.dedicated_poll_thread {
+        poll_on_thread(config.clone(), shared.clone()).boxed_local()
+    } else {
+        poll_api(config.clone(), shared.clone()).boxed_local()
+    };
+    log::debug!("Polling API stream.");
+    let mut receivers_gone = false;
+    let mut readings_log = config
+        .readings_log_file
+        .clone()
+        .map(|path| ReadingsLog::new(path, config.rotate_readings_log));
+    let mut sqlite = match &config.sqlite_path {
+        Some(path) => Some(SqliteSink::open(path, config.renewable_fuels.clone())?),
+        None => None,
+    };
+    let deadline = opt.max_runtime.map(|d| tokio::time::Instant::now() + d);
     loop {
-        if intensity_rx.changed().await.is_ok() {
-            let res = *intensity_rx.borrow();
-            if let Some(intensity) = res {
-                tweet(&config, intensity)
-                    .await
-                    .map_err(anyhow::Error::msg)?;
+        // `None` once the deadline has passed.
+        let poll = async {
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, stream.next()).await.ok(),
+                None => Some(stream.next().await),
+            }
+        };
+        let next = tokio::select! {
+            next = poll => next,
+            // Notifiers only finish early in fail-fast mode if they failed,
+            // or if there's nothing for them to do.
+            Some(res) = notifiers.join_next(), if config.fail_fast => {
+                if let Err(e) = res? {
+                    exit_fail_fast(e.exit_code(), &e);
+                }
+                continue;
+            }
+        };
+        let Some(next) = next else {
+            log::info!("Maximum runtime reached, shutting down.");
+            return shutdown(tx, notifiers).await;
+        };
+        let Some(n) = next else {
+            break;
+        };
+        log::debug!("Received new data: {:?}", &n);
+        let counter = match &n {
+            Ok(_) => &shared.counters.polls,
+            Err(_) => &shared.counters.poll_failures,
+        };
+        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if let (true, Err(e)) = (config.fail_fast, &n) {
+            exit_fail_fast(EXIT_POLL_FAILED, format!("Failed to poll the API: {}", e));
+        }
+        if let (Some(readings_log), Ok(snapshot)) = (&mut readings_log, &n) {
+            readings_log.append(snapshot, chrono::Utc::now()).await;
+        }
+        if let (Some(sqlite), Ok(snapshot)) = (&mut sqlite, &n) {
+            sqlite.insert(snapshot, chrono::Utc::now()).await;
+        }
+        tx.send(n.ok());
+        if tx.is_closed() && !receivers_gone {
+            receivers_gone = true;
+            // Each notifier's receivers go with its task, so they've all
+            // finished or are about to.
+            let mut exited = Vec::new();
+            while let Some(res) = notifiers.join_next().await {
+                exited.push(match res? {
+                    Ok(name) => name,
+                    Err(e) => e.name,
+                });
             }
+            let exited = exited.join(", ");
+            if config.exit_on_no_receivers {
+                log::error!("All notifiers have exited ({}), shutting down.", exited);
+                return Err("No notifiers left".into());
+            }
+            log::warn!(
+                "All notifiers have exited ({}), but polling continues.",
+                exited
+            );
         }
-        tokio::time::sleep(std::time::Duration::from_secs(config.tweet_interval_secs)).await;
     }
+    // Polling keeps going through failures, so it only stops if it couldn't
+    // get started.
+    shutdown(tx, notifiers).await?;
+    Err("Polling stopped".into())
 }
 
-async fn tweet(
-    config: &Config,
-    intensity: IntensityResponse,
-) -> Result<egg_mode::Response<egg_mode::tweet::Tweet>, egg_mode::error::Error> {
-    let con_token = egg_mode::KeyPair::new(
-        config.twitter_consumer_key.to_string(),
-        config.twitter_consumer_secret.to_string(),
-    );
-    let access_token = egg_mode::KeyPair::new(
-        config.twitter_access_token.to_string(),
-        config.twitter_access_secret.to_string(),
-    );
-    let token = egg_mode::Token::Access {
-        consumer: con_token,
-        access: access_token,
-    };
+/// Reads the config from `path`, retrying up to `retries` times, or from
+/// stdin if that's `-`, which keeps secrets out of the filesystem.
+async fn read_config(
+    path: &std::path::Path,
+    retries: u32,
+) -> Result<String, Box<dyn std::error::Error>> {
+    use tokio::io::AsyncReadExt;
 
-    use egg_mode::tweet::DraftTweet;
+    if !is_stdin(path) {
+        let policy = RetryPolicy {
+            attempts: retries + 1,
+            backoff_secs: 1,
+        };
+        return retry(&policy, || tokio::fs::read_to_string(path), |_| true)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to read config {} after {} attempts: {}",
+                    path.display(),
+                    policy.attempts,
+                    e
+                )
+                .into()
+            });
+    }
+    let mut config = String::new();
+    tokio::io::stdin()
+        .read_to_string(&mut config)
+        .await
+        .map_err(|e| format!("Failed to read config from stdin: {}", e))?;
+    if config.trim().is_empty() {
+        return Err("No config on stdin".into());
+    }
+    Ok(config)
+}
 
-    let post = DraftTweet::new(format!(
-        "The current carbon intensity for London is {:?} with approximately {} gCO2/KWh.",
-        intensity.index, intensity.forecast
-    ))
-    .send(&token)
-    .await?;
+fn is_stdin(path: &std::path::Path) -> bool {
+    path == std::path::Path::new("-")
+}
 
-    dbg!(&post);
+/// Where the config came from, for error messages.
+fn config_source(path: &std::path::Path) -> std::borrow::Cow<'_, str> {
+    if is_stdin(path) {
+        "from stdin".into()
+    } else {
+        path.to_string_lossy()
+    }
+}
 
-    Ok(post)
+/// Exit code in fail-fast mode when polling the API failed.
+const EXIT_POLL_FAILED: i32 = 2;
+/// Exit code in fail-fast mode when the MQTT task failed.
+const EXIT_MQTT_FAILED: i32 = 3;
+/// Exit code in fail-fast mode when the Twitter task failed.
+const EXIT_TWITTER_FAILED: i32 = 4;
+/// Exit code in fail-fast mode when the Bluesky task failed.
+const EXIT_BLUESKY_FAILED: i32 = 5;
+/// Exit code in fail-fast mode when the metrics server or StatsD task failed.
+const EXIT_METRICS_FAILED: i32 = 6;
+
+/// Exits straight away with `code`, without waiting for anything to wind
+/// down.
+fn exit_fail_fast(code: i32, reason: impl std::fmt::Display) -> ! {
+    log::error!("{}", reason);
+    log::error!("Exiting with code {} on the first error.", code);
+    std::process::exit(code)
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// How long `shutdown` waits for the notifiers. The tweeter may be sleeping
+/// between tweets, so we don't wait forever.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
-    #[test]
-    fn test_timestamp() {
-        let j = r#"
-{
-    "data": [
-        {
-            "regionid": 13,
-            "dnoregion": "UKPN London",
-            "shortname": "London",
-            "data": [
-                {
-                    "from": "2021-12-13T16:30Z",
-                    "to": "2021-12-13T17:00Z",
-                    "intensity": {
-                        "forecast": 435,
-                        "index": "very high"
-                    },
-                    "generationmix": [
-                        {
-                            "fuel": "biomass",
-                            "perc": 0
-                        },
-                        {
-                            "fuel": "coal",
-                            "perc": 0.1
-                        },
-                        {
-                            "fuel": "imports",
-                            "perc": 84.1
-                        },
-                        {
-                            "fuel": "gas",
-                            "perc": 8.9
-                        },
-                        {
-                            "fuel": "nuclear",
-                            "perc": 2.4
-                        },
-                        {
-                            "fuel": "other",
-                            "perc": 0
-                        },
-                        {
-                            "fuel": "hydro",
-                            "perc": 0.2
-                        },
-                        {
-                            "fuel": "solar",
-                            "perc": 0
-                        },
-                        {
-                            "fuel": "wind",
-                            "perc": 4.3
-                        }
-                    ]
-                }
-            ]
+/// Closes the channel so that the notifiers wind down, and waits a while for
+/// them to do so.
+async fn shutdown(
+    tx: SnapshotTx,
+    mut notifiers: tokio::task::JoinSet<Result<&'static str, NotifierFailed>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    drop(tx);
+    let finished = async { while notifiers.join_next().await.is_some() {} };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, finished)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Notifiers didn't finish within {:?}, exiting anyway.",
+            SHUTDOWN_TIMEOUT
+        );
+    }
+    Ok(())
+}
+
+/// A notifier task that has been given up on.
+#[derive(Debug)]
+struct NotifierFailed {
+    name: &'static str,
+    /// Why its last run failed.
+    error: String,
+}
+
+impl NotifierFailed {
+    fn exit_code(&self) -> i32 {
+        match self.name {
+            "MQTT" => EXIT_MQTT_FAILED,
+            "Twitter" | "Daily bar" => EXIT_TWITTER_FAILED,
+            "Metrics" | "StatsD" => EXIT_METRICS_FAILED,
+            _ => EXIT_BLUESKY_FAILED,
         }
-    ]
+    }
 }
-        "#;
-        let jd = &mut serde_json::Deserializer::from_str(j);
-        let res: RegionalResponse = serde_path_to_error::deserialize(jd).unwrap();
-        insta::assert_debug_snapshot!(res);
+
+impl std::fmt::Display for NotifierFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} task failed: {}", self.name, self.error)
     }
+}
 
-    #[test]
-    fn test_error() {
-        let j = r#"
+/// Runs a notifier task created by `task`, and restarts it with backoff
+/// whenever it fails or panics, up to `max_restarts` times. Returns `name`
+/// once the task has exited normally, which it only does when there's
+/// nothing left to notify about, or its last error once it has been given up
+/// on.
+async fn supervise_notifier<F, Fut>(
+    name: &'static str,
+    max_restarts: u32,
+    task: F,
+) -> Result<&'static str, NotifierFailed>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), Box<dyn std::error::Error + 'static + Send>>> + Send + 'static,
 {
-    "error": {
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    let mut backoff = MIN_BACKOFF;
+    let mut restarts = 0;
+    loop {
+        // Spawned so that a panic doesn't take the supervisor down with it.
+        let error = match tokio::task::spawn(task()).await {
+            Ok(Ok(())) => {
+                log::warn!("{} task exited.", name);
+                return Ok(name);
+            }
+            Ok(Err(e)) => {
+                log::error!("{} task failed: {}", name, e);
+                e.to_string()
+            }
+            Err(e) => {
+                log::error!("{} task panicked: {}", name, e);
+                e.to_string()
+            }
+        };
+        if restarts >= max_restarts {
+            log::error!("Giving up on {} task after {} restarts.", name, restarts);
+            return Err(NotifierFailed { name, error });
+        }
+        restarts += 1;
+        log::warn!(
+            "Restarting {} task in {:?} (restart {} of {}).",
+            name,
+            backoff,
+            restarts,
+            max_restarts
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// How long a panic report may hold up the thread that panicked.
+const PANIC_REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `Config::report_panics`: after the default hook has printed a panic, logs
+/// it and posts it with a backtrace to `url`. Posting is best-effort, and
+/// gives up after `PANIC_REPORT_TIMEOUT`.
+fn install_panic_hook(url: String, instance: String) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        log::error!("{}", info);
+        let report = panic_report(&instance, info, &std::backtrace::Backtrace::force_capture());
+        let url = url.clone();
+        // The panicking thread may be a runtime worker, which can't block on
+        // a request, so the report goes out from a thread of its own.
+        let post = std::thread::spawn(move || {
+            let result = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(anyhow::Error::from)
+                .and_then(|runtime| runtime.block_on(post_panic_report(&url, &report)));
+            if let Err(e) = result {
+                log::warn!("Couldn't report panic: {:#}", e);
+            }
+        });
+        let _ = post.join();
+    }));
+}
+
+/// The body posted to `Config::alert_webhook_url` for a panic.
+fn panic_report(
+    instance: &str,
+    panic: &dyn std::fmt::Display,
+    backtrace: &dyn std::fmt::Display,
+) -> serde_json::Value {
+    serde_json::json!({
+        "text": format!("carbon-alert on {}: {}\n\n{}", instance, panic, backtrace),
+    })
+}
+
+async fn post_panic_report(url: &str, report: &serde_json::Value) -> anyhow::Result<()> {
+    reqwest::Client::builder()
+        .timeout(PANIC_REPORT_TIMEOUT)
+        .build()?
+        .post(url)
+        .json(report)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Aborts a task when dropped, so that it can't outlive its owner even if
+/// that panics.
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Upstream responses held by the relay, by path and query, with when they
+/// were cached and when they expire.
+#[derive(Debug)]
+struct RelayCache {
+    entries: std::collections::HashMap<
+        String,
+        (
+            chrono::DateTime<chrono::Utc>,
+            chrono::DateTime<chrono::Utc>,
+            String,
+        ),
+    >,
+    max_entries: usize,
+    /// How long responses are kept at most, if less than until the next
+    /// half hour.
+    max_age: Option<Duration>,
+    size: BufferSize,
+}
+
+impl RelayCache {
+    fn new(max_entries: usize) -> Self {
+        RelayCache {
+            entries: std::collections::HashMap::new(),
+            max_entries: max_entries.max(1),
+            max_age: None,
+            size: BufferSize::default(),
+        }
+    }
+
+    fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Accounts for the cached responses in `size`.
+    fn with_buffer_size(mut self, size: BufferSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    fn get(&self, path: &str, now: chrono::DateTime<chrono::Utc>) -> Option<&str> {
+        match self.entries.get(path) {
+            Some((_, expires, body)) if now < *expires => Some(body),
+            _ => None,
+        }
+    }
+
+    /// Caches `body` until the start of the next half hour, when the API
+    /// moves on to a new slot, or for `max_age` if that's sooner.
+    fn insert(&mut self, path: String, body: String, now: chrono::DateTime<chrono::Utc>) {
+        // Paths with timestamps in them are never asked for again once
+        // they've expired, so they'd pile up otherwise.
+        let before = self.entries.len();
+        self.entries.retain(|_, (_, expires, _)| now < *expires);
+        while self.entries.len() >= self.max_entries && !self.entries.contains_key(&path) {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (cached, _, _))| *cached)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        let mut expires = next_half_hour(now);
+        if let Some(max_age) = self
+            .max_age
+            .and_then(|age| chrono::Duration::from_std(age).ok())
+        {
+            expires = expires.min(now + max_age);
+        }
+        self.entries.insert(path, (now, expires, body));
+        self.size.resize(before, self.entries.len());
+    }
+}
+
+impl Drop for RelayCache {
+    fn drop(&mut self) {
+        self.size.resize(self.entries.len(), 0);
+    }
+}
+
+/// How long any HTTP request may take, so that a hung one can't hold up
+/// polls or relay clients forever.
+const HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One lock per path being relayed, so that instances asking for the same
+/// thing at once only cause one upstream request, without holding up those
+/// asking for something else.
+#[derive(Debug, Default)]
+struct RelayLocks(
+    std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<()>>>>,
+);
+
+impl RelayLocks {
+    fn get(&self, path: &str) -> std::sync::Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.0.lock().unwrap();
+        locks.entry(path.to_string()).or_default().clone()
+    }
+
+    /// Forgets the lock for `path` once nobody but the caller holds on to it.
+    fn release(&self, path: &str, lock: std::sync::Arc<tokio::sync::Mutex<()>>) {
+        let mut locks = self.0.lock().unwrap();
+        // The map's and the caller's.
+        if std::sync::Arc::strong_count(&lock) <= 2 {
+            locks.remove(path);
+        }
+    }
+}
+
+fn next_half_hour(now: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+
+    const HALF_HOUR: i64 = 30 * 60;
+    chrono::Utc.timestamp((now.timestamp() / HALF_HOUR + 1) * HALF_HOUR, 0)
+}
+
+/// Serves the API from `config.api_base_url` on `config.relay_listen`, see
+/// `Config::relay_mode`.
+async fn run_relay(config: &Config, shared: std::sync::Arc<Shared>) -> anyhow::Result<()> {
+    let client = config.http_client()?;
+    let base_url: std::sync::Arc<str> = config.api_base_url.as_str().into();
+    let mut cache = RelayCache::new(config.buffer_limit(config.buffer_limits.relay_cache))
+        .with_buffer_size(shared.buffer_sizes.relay_cache.clone());
+    if let Some(max_age) = config.max_buffer_age() {
+        cache = cache.with_max_age(max_age);
+    }
+    let cache = std::sync::Arc::new(std::sync::Mutex::new(cache));
+    let locks = std::sync::Arc::new(RelayLocks::default());
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let (client, base_url) = (client.clone(), base_url.clone());
+        let (cache, locks, shared) = (cache.clone(), locks.clone(), shared.clone());
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                relay(
+                    req,
+                    client.clone(),
+                    base_url.clone(),
+                    cache.clone(),
+                    locks.clone(),
+                    shared.clone(),
+                )
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&config.relay_listen)?.serve(make_service);
+    log::info!(
+        "Relaying {} on {}.",
+        config.api_base_url,
+        server.local_addr()
+    );
+    server.await?;
+    Ok(())
+}
+
+/// Serves the latest readings on `config.metrics_listen` in Prometheus' text
+/// format, until the channel closes.
+async fn run_metrics(
+    config: Config,
+    intensity_rx: tokio::sync::watch::Receiver<Option<Snapshot>>,
+    shared: std::sync::Arc<Shared>,
+) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
+    let Some(addr) = config.metrics_listen else {
+        return Ok(());
+    };
+    let latest = intensity_rx.clone();
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let (latest, shared) = (latest.clone(), shared.clone());
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |_req| {
+                let body = metrics_text(
+                    latest.borrow().as_ref(),
+                    shared.counters.values(),
+                    shared.buffer_sizes.values(),
+                    chrono::Utc::now(),
+                );
+                async move {
+                    let mut response = hyper::Response::new(hyper::Body::from(body));
+                    response.headers_mut().insert(
+                        hyper::header::CONTENT_TYPE,
+                        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                    );
+                    Ok::<_, std::convert::Infallible>(response)
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?
+        .serve(make_service);
+    log::info!("Serving metrics on {}.", server.local_addr());
+    let mut closed = intensity_rx;
+    server
+        .with_graceful_shutdown(async move { while closed.changed().await.is_ok() {} })
+        .await
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)
+}
+
+/// The metrics for `snapshot`, one series per region, and per fuel for the
+/// generation mix, followed by `counters` and the size of each of `buffers`.
+/// Before the first reading, there are no series for the readings.
+fn metrics_text(
+    snapshot: Option<&Snapshot>,
+    counters: CounterValues,
+    buffers: [(&str, usize); 3],
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    use std::fmt::Write;
+
+    let readings: Vec<&Reading> = snapshot
+        .map(|s| std::iter::once(&s.primary).chain(&s.others).collect())
+        .unwrap_or_default();
+    let mut out = String::new();
+    out.push_str(
+        "# HELP carbon_intensity_grams_per_kwh The reported carbon intensity.\n\
+         # TYPE carbon_intensity_grams_per_kwh gauge\n",
+    );
+    for reading in &readings {
+        let _ = writeln!(
+            out,
+            "carbon_intensity_grams_per_kwh{{region=\"{}\"}} {}",
+            reading.region as u16, reading.intensity.forecast.0
+        );
+    }
+    out.push_str(
+        "# HELP carbon_generation_mix_percent Share of generation per fuel.\n\
+         # TYPE carbon_generation_mix_percent gauge\n",
+    );
+    for reading in &readings {
+        for share in &reading.generation_mix {
+            // Debug formatting escapes quotes and backslashes the way
+            // Prometheus wants them.
+            let _ = writeln!(
+                out,
+                "carbon_generation_mix_percent{{region=\"{}\",fuel={:?}}} {}",
+                reading.region as u16, share.fuel, share.perc
+            );
+        }
+    }
+    if let Some(snapshot) = snapshot {
+        let _ = writeln!(
+            out,
+            "# HELP carbon_alert_staleness_seconds Seconds since the last successful poll.\n\
+             # TYPE carbon_alert_staleness_seconds gauge\n\
+             carbon_alert_staleness_seconds {}",
+            snapshot.staleness_secs(now)
+        );
+    }
+    for (name, help, value) in counters.named() {
+        let _ = writeln!(
+            out,
+            "# HELP carbon_alert_{0}_total {1}\n\
+             # TYPE carbon_alert_{0}_total counter\n\
+             carbon_alert_{0}_total {2}",
+            name, help, value
+        );
+    }
+    out.push_str(
+        "# HELP carbon_alert_buffer_entries Entries held in memory, per buffer.\n\
+         # TYPE carbon_alert_buffer_entries gauge\n",
+    );
+    for (buffer, entries) in buffers {
+        let _ = writeln!(
+            out,
+            "carbon_alert_buffer_entries{{buffer=\"{}\"}} {}",
+            buffer, entries
+        );
+    }
+    out
+}
+
+/// What the poller, the relay and the notifiers share while running: the
+/// API rate limit, and what `run_metrics` and `run_statsd` report. `main`
+/// creates it and hands each of them a reference.
+#[derive(Debug, Default)]
+struct Shared {
+    api_rate_limit: RateLimiter,
+    counters: Counters,
+    buffer_sizes: BufferSizes,
+}
+
+/// How many entries the in-memory buffers hold, for `run_metrics`. Each
+/// buffer accounts for its own, so those of the same kind add up.
+#[derive(Debug, Default)]
+struct BufferSizes {
+    smoothing: BufferSize,
+    rate_alert: BufferSize,
+    relay_cache: BufferSize,
+}
+
+impl BufferSizes {
+    /// Each kind of buffer with its size.
+    fn values(&self) -> [(&'static str, usize); 3] {
+        [
+            ("smoothing", self.smoothing.get()),
+            ("rate_alert", self.rate_alert.get()),
+            ("relay_cache", self.relay_cache.get()),
+        ]
+    }
+}
+
+/// The entries held by buffers of one kind, see `BufferSizes`. Buffers
+/// start out with one of their own, which nothing reports.
+#[derive(Debug, Clone, Default)]
+struct BufferSize(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+impl BufferSize {
+    /// Records that a buffer went from `before` entries to `after`.
+    fn resize(&self, before: usize, after: usize) {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        if after > before {
+            self.0.fetch_add(after - before, Relaxed);
+        } else {
+            self.0.fetch_sub(before - after, Relaxed);
+        }
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Totals since startup, for `run_metrics` and `run_statsd`.
+#[derive(Debug, Default)]
+struct Counters {
+    polls: std::sync::atomic::AtomicU64,
+    poll_failures: std::sync::atomic::AtomicU64,
+    tweets: std::sync::atomic::AtomicU64,
+    bluesky_posts: std::sync::atomic::AtomicU64,
+    dead_letters: std::sync::atomic::AtomicU64,
+    failovers: std::sync::atomic::AtomicU64,
+}
+
+impl Counters {
+    fn values(&self) -> CounterValues {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        CounterValues {
+            polls: self.polls.load(Relaxed),
+            poll_failures: self.poll_failures.load(Relaxed),
+            tweets: self.tweets.load(Relaxed),
+            bluesky_posts: self.bluesky_posts.load(Relaxed),
+            dead_letters: self.dead_letters.load(Relaxed),
+            failovers: self.failovers.load(Relaxed),
+        }
+    }
+}
+
+/// The `Counters` at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct CounterValues {
+    polls: u64,
+    poll_failures: u64,
+    tweets: u64,
+    bluesky_posts: u64,
+    dead_letters: u64,
+    failovers: u64,
+}
+
+impl CounterValues {
+    /// Each counter's name and description, with its value.
+    fn named(self) -> [(&'static str, &'static str, u64); 6] {
+        [
+            ("polls", "Successful polls since startup.", self.polls),
+            (
+                "poll_failures",
+                "Failed polls since startup.",
+                self.poll_failures,
+            ),
+            ("tweets", "Tweets posted since startup.", self.tweets),
+            (
+                "bluesky_posts",
+                "Bluesky posts since startup.",
+                self.bluesky_posts,
+            ),
+            (
+                "dead_letters",
+                "Notifications given up on since startup.",
+                self.dead_letters,
+            ),
+            (
+                "failovers",
+                "Switches to api_fallback_url since startup.",
+                self.failovers,
+            ),
+        ]
+    }
+}
+
+/// How long a StatsD send may wait for the socket.
+const STATSD_SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Sends metrics to a StatsD agent, see `Config::statsd_addr`.
+struct Statsd {
+    socket: tokio::net::UdpSocket,
+    prefix: String,
+    /// The counters as last sent, as StatsD wants increments.
+    sent: CounterValues,
+}
+
+impl Statsd {
+    async fn connect(addr: &str, prefix: &str) -> std::io::Result<Self> {
+        let target = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} didn't resolve", addr),
+            )
+        })?;
+        let local: std::net::SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = tokio::net::UdpSocket::bind(local).await?;
+        socket.connect(target).await?;
+        Ok(Statsd {
+            socket,
+            prefix: prefix.to_string(),
+            sent: CounterValues::default(),
+        })
+    }
+
+    /// Sends `snapshot` and whatever `counters` went up by since the last
+    /// time, in one datagram. This waits no longer than
+    /// `STATSD_SEND_TIMEOUT`: if the agent is down or the socket is busy, the
+    /// datagram is dropped, as there's nobody to tell about it but the log.
+    async fn send(&mut self, snapshot: &Snapshot, counters: CounterValues) {
+        let lines = statsd_lines(&self.prefix, snapshot, counters, self.sent);
+        self.sent = counters;
+        let datagram = lines.join("\n");
+        match tokio::time::timeout(STATSD_SEND_TIMEOUT, self.socket.send(datagram.as_bytes())).await
+        {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::debug!("Failed to send to StatsD: {}", e),
+            Err(_) => log::debug!("Timed out sending to StatsD."),
+        }
+    }
+}
+
+/// The StatsD lines for `snapshot`: gauges for the forecast and index of each
+/// region, tagged with its ID, and a counter for each of `counters` that
+/// went up since `sent`, all named starting with `prefix`.
+fn statsd_lines(
+    prefix: &str,
+    snapshot: &Snapshot,
+    counters: CounterValues,
+    sent: CounterValues,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    for reading in std::iter::once(&snapshot.primary).chain(&snapshot.others) {
+        let region = reading.region as u16;
+        lines.push(format!(
+            "{}.intensity:{}|g|#region:{}",
+            prefix, reading.intensity.forecast.0, region
+        ));
+        lines.push(format!(
+            "{}.index:{}|g|#region:{}",
+            prefix, reading.intensity.index as u8, region
+        ));
+    }
+    for ((name, _, value), (_, _, before)) in counters.named().into_iter().zip(sent.named()) {
+        if value > before {
+            lines.push(format!("{}.{}:{}|c", prefix, name, value - before));
+        }
+    }
+    lines
+}
+
+/// Sends each snapshot to `config.statsd_addr` until the channel closes.
+async fn run_statsd(
+    config: Config,
+    mut intensity_rx: tokio::sync::watch::Receiver<Option<Snapshot>>,
+    shared: std::sync::Arc<Shared>,
+) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
+    let Some(addr) = &config.statsd_addr else {
+        return Ok(());
+    };
+    let mut statsd = Statsd::connect(addr, &config.statsd_prefix)
+        .await
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+    log::info!("Sending metrics to StatsD at {}.", addr);
+    let mut last_seq = 0;
+    while let Some(snapshot) = next_snapshot(&mut intensity_rx, "StatsD", &mut last_seq).await {
+        statsd.send(&snapshot, shared.counters.values()).await;
+    }
+    Ok(())
+}
+
+async fn relay(
+    req: hyper::Request<hyper::Body>,
+    client: reqwest::Client,
+    base_url: std::sync::Arc<str>,
+    cache: std::sync::Arc<std::sync::Mutex<RelayCache>>,
+    locks: std::sync::Arc<RelayLocks>,
+    shared: std::sync::Arc<Shared>,
+) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+    let respond = |status: hyper::StatusCode, body: String| {
+        let mut response = hyper::Response::new(hyper::Body::from(body));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/json"),
+        );
+        Ok::<_, std::convert::Infallible>(response)
+    };
+    if req.method() != hyper::Method::GET {
+        return respond(hyper::StatusCode::METHOD_NOT_ALLOWED, String::new());
+    }
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |p| p.as_str())
+        .to_string();
+    let lock = locks.get(&path);
+    let guard = lock.lock().await;
+    let response = relay_path(&path, &client, &shared.api_rate_limit, &base_url, &cache).await;
+    drop(guard);
+    locks.release(&path, lock);
+    respond(response.0, response.1)
+}
+
+/// Answers a request for `path` from the cache or upstream, as a status and
+/// body. Callers hold the lock for `path`.
+async fn relay_path(
+    path: &str,
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    base_url: &str,
+    cache: &std::sync::Mutex<RelayCache>,
+) -> (hyper::StatusCode, String) {
+    let now = chrono::Utc::now();
+    if let Some(body) = cache.lock().unwrap().get(path, now) {
+        log::debug!("Relaying {} from the cache.", path);
+        return (hyper::StatusCode::OK, body.to_string());
+    }
+    let upstream = async {
+        rate_limit.acquire().await;
+        let resp = client.get(format!("{}{}", base_url, path)).send().await?;
+        let status = resp.status();
+        Ok::<_, reqwest::Error>((status, resp.text().await?))
+    };
+    match upstream.await {
+        Ok((status, body)) => {
+            log::debug!("Relaying {} from upstream: {}", path, status);
+            if status.is_success() {
+                cache
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_string(), body.clone(), now);
+            }
+            (
+                hyper::StatusCode::from_u16(status.as_u16())
+                    .unwrap_or(hyper::StatusCode::BAD_GATEWAY),
+                body,
+            )
+        }
+        Err(e) => {
+            log::warn!("Failed to relay {}: {}", path, e);
+            (hyper::StatusCode::BAD_GATEWAY, String::new())
+        }
+    }
+}
+
+fn poll_api(
+    config: Config,
+    shared: std::sync::Arc<Shared>,
+) -> impl futures_core::Stream<Item = Result<Snapshot, Box<dyn std::error::Error>>> {
+    async_stream::stream! {
+        let poller = match Poller::new(config.clone(), shared) {
+            Ok(poller) => poller,
+            Err(e) => {
+                yield Err(e);
+                return;
+            }
+        };
+        let first_delay = splay(config.startup_splay_secs);
+        let interval = Duration::from_secs(config.poll_interval_secs);
+        for await snapshot in poll_loop(poller, first_delay, interval) {
+            yield snapshot;
+        }
+    }
+}
+
+/// Runs `poll_api` on a thread with a single-threaded runtime of its own,
+/// passing its snapshots back. The thread stops once the returned stream is
+/// dropped and the next snapshot is ready.
+fn poll_on_thread(
+    config: Config,
+    shared: std::sync::Arc<Shared>,
+) -> impl futures_core::Stream<Item = Result<Snapshot, Box<dyn std::error::Error>>> {
+    // Errors aren't `Send`, so only their messages make it across.
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<Snapshot, String>>(1);
+    let spawned = std::thread::Builder::new()
+        .name("poller".to_string())
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    let _ =
+                        tx.blocking_send(Err(format!("Failed to start the poll runtime: {}", e)));
+                    return;
+                }
+            };
+            runtime.block_on(async {
+                let stream = poll_api(config, shared);
+                futures_util::pin_mut!(stream);
+                while let Some(res) = stream.next().await {
+                    if tx.send(res.map_err(|e| e.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        });
+    async_stream::stream! {
+        // The sender went down with the closure, so report why and stop.
+        if let Err(e) = spawned {
+            yield Err(format!("Failed to spawn the poll thread: {}", e).into());
+            return;
+        }
+        while let Some(res) = rx.recv().await {
+            yield res.map_err(Into::into);
+        }
+    }
+}
+
+/// Takes one snapshot per call, so that `poll_loop` can be tested without
+/// the API.
+trait Sample {
+    fn sample(
+        &mut self,
+    ) -> futures_util::future::LocalBoxFuture<'_, Result<Snapshot, Box<dyn std::error::Error>>>;
+}
+
+/// Samples `sampler` after `first_delay`, and then `interval` after each
+/// sample finished. After a failed sample, the next one comes sooner, with
+/// a backoff that doubles up to `interval` while the failures go on.
+fn poll_loop<S: Sample>(
+    mut sampler: S,
+    first_delay: Duration,
+    interval: Duration,
+) -> impl futures_core::Stream<Item = Result<Snapshot, Box<dyn std::error::Error>>> {
+    const MIN_BACKOFF: Duration = Duration::from_secs(15);
+
+    async_stream::stream! {
+        if !first_delay.is_zero() {
+            log::info!("Delaying the first poll by {:?}.", first_delay);
+            tokio::time::sleep(first_delay).await;
+        }
+        let mut backoff = MIN_BACKOFF.min(interval);
+        loop {
+            let res = sampler.sample().await;
+            let delay = match &res {
+                Ok(_) => {
+                    backoff = MIN_BACKOFF.min(interval);
+                    interval
+                }
+                Err(e) => {
+                    log::warn!("Failed to poll the API, retrying in {:?}: {}", backoff, e);
+                    let delay = backoff;
+                    backoff = (backoff * 2).min(interval);
+                    delay
+                }
+            };
+            yield res;
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Polls the API for `poll_api`, keeping what has to carry over between
+/// polls.
+struct Poller {
+    config: Config,
+    /// The regions that are reported individually, the first of which is
+    /// the primary one.
+    regions: Vec<RegionId>,
+    /// `regions` plus any weighted ones.
+    fetched: Vec<RegionId>,
+    client: reqwest::Client,
+    fallback_client: reqwest::Client,
+    on_fallback: bool,
+    seq: u64,
+    smoother: Option<Smoother>,
+    shared: std::sync::Arc<Shared>,
+}
+
+impl Poller {
+    fn new(
+        config: Config,
+        shared: std::sync::Arc<Shared>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let regions = config.monitored_regions()?;
+        // Weighted regions are fetched along with the monitored ones, but
+        // only the latter are reported individually.
+        let mut fetched = regions.clone();
+        for &(region, _) in &config.regions_weighted {
+            if !fetched.contains(&region) {
+                fetched.push(region);
+            }
+        }
+        let smoother = config.smoothing_window.map(|window| {
+            let mut smoother = Smoother::new(window)
+                .with_warmup(config.warmup_samples)
+                .with_max_entries(config.buffer_limit(config.buffer_limits.smoothing))
+                .with_buffer_size(shared.buffer_sizes.smoothing.clone());
+            if let Some(max_age) = config.max_buffer_age() {
+                smoother = smoother.with_max_age(max_age);
+            }
+            smoother
+        });
+        Ok(Poller {
+            client: config.http_client()?,
+            fallback_client: config.plain_http_client()?,
+            config,
+            regions,
+            fetched,
+            on_fallback: false,
+            seq: 0,
+            smoother,
+            shared,
+        })
+    }
+
+    /// Fetches the configured regions once and turns them into the next
+    /// snapshot.
+    async fn poll_once(&mut self) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        let config = &self.config;
+        let polled = fetch_with_failover(
+            &self.client,
+            &self.fallback_client,
+            config,
+            &self.shared,
+            &self.fetched,
+            &mut self.on_fallback,
+        )
+        .await?;
+        for reading in &polled {
+            log::debug!(
+                "{}: {:?} for {} to {}",
+                reading.shortname,
+                reading.intensity,
+                reading.from,
+                reading.to
+            );
+            if config.log_each_poll {
+                log::info!("{}", poll_summary(reading));
+            }
+        }
+        // Smoothed once per poll, whichever sources need it.
+        let smoothed = self.smoother.as_mut().map(|smoother| {
+            let mut smoothed = polled.clone();
+            for reading in &mut smoothed {
+                smoother.apply(reading);
+            }
+            smoothed
+        });
+        let mut readings = sourced(&polled, smoothed.as_deref(), config.publish_source());
+        let blended = if config.regions_weighted.is_empty() {
+            None
+        } else {
+            Some(blend(&config.regions_weighted, &readings)?)
+        };
+        let notified = if config.notification_source() == config.publish_source() {
+            None
+        } else {
+            let readings = sourced(&polled, smoothed.as_deref(), config.notification_source());
+            Some(if config.regions_weighted.is_empty() {
+                readings[0].clone()
+            } else {
+                blend(&config.regions_weighted, &readings)?.reading
+            })
+        };
+        readings.truncate(self.regions.len());
+        let primary = readings.remove(0);
+        let window = if config.mqtt.forecast_window {
+            // The window is a nice-to-have, so it's not worth failing the
+            // poll over.
+            let (client, base_url) = match &config.api_fallback_url {
+                Some(fallback) if self.on_fallback => (&self.fallback_client, fallback),
+                _ => (&self.client, &config.api_base_url),
+            };
+            match fetch_forecast_window(
+                client,
+                &self.shared.api_rate_limit,
+                base_url,
+                primary.region,
+                chrono::Utc::now(),
+            )
+            .await
+            {
+                Ok(window) => Some(window),
+                Err(e) => {
+                    log::warn!("Failed to fetch the forecast window: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let polled_at = chrono::Utc::now();
+        self.seq += 1;
+        Ok(Snapshot {
+            primary,
+            others: readings,
+            blended,
+            window,
+            notified,
+            polled_at,
+            seq: self.seq,
+        })
+    }
+}
+
+impl Sample for Poller {
+    fn sample(
+        &mut self,
+    ) -> futures_util::future::LocalBoxFuture<'_, Result<Snapshot, Box<dyn std::error::Error>>>
+    {
+        Box::pin(self.poll_once())
+    }
+}
+
+/// One line about a polled reading for `Config::log_each_poll`, like
+/// "London: high (280 gCO2/kWh) at 16:30Z".
+fn poll_summary(reading: &Reading) -> String {
+    format!(
+        "{}: {} ({}) at {}",
+        reading.shortname,
+        reading.intensity.index.label(),
+        reading.intensity.forecast,
+        reading.from.format("%H:%MZ")
+    )
+}
+
+/// How many upcoming polls and tweets `--print-effective-schedule` lists.
+const SCHEDULE_PREVIEW: usize = 5;
+
+/// What `--print-effective-schedule` prints: how polls and tweets are
+/// scheduled, and when the next ones would be if started at `now`. It assumes
+/// the longest startup splay, that requests take no time, and, for tweets,
+/// that the index changes at every poll.
+fn schedule_text(config: &Config, now: chrono::DateTime<chrono::Utc>) -> String {
+    use std::fmt::Write;
+
+    let time = |t: chrono::DateTime<chrono::Utc>| t.format("%Y-%m-%d %H:%M:%SZ");
+    let secs = |secs: u64| chrono::Duration::seconds(secs as i64);
+    let first_poll = now + secs(config.startup_splay_secs);
+    let polls = (0..).map(|n| first_poll + secs(config.poll_interval_secs) * n);
+
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "Polls every {}s, counted from when the last one finished.",
+        config.poll_interval_secs
+    );
+    if !config.allow_aggressive_polling {
+        let _ = writeln!(
+            text,
+            "Intervals below {}s are raised to that, see allow_aggressive_polling.",
+            MIN_POLL_INTERVAL_SECS
+        );
+    }
+    if config.startup_splay_secs > 0 {
+        let _ = writeln!(
+            text,
+            "The first poll waits a random 0 to {}s.",
+            config.startup_splay_secs
+        );
+    }
+    if let Some(per_minute) = config.api_requests_per_minute {
+        let _ = writeln!(text, "At most {} API requests a minute.", per_minute);
+    }
+    let _ = writeln!(text, "Next polls:");
+    for poll in polls.clone().take(SCHEDULE_PREVIEW) {
+        let _ = writeln!(text, "  {}", time(poll));
+    }
+
+    if !config.twitter_enabled {
+        let _ = writeln!(text, "Twitter is disabled.");
+        return text;
+    }
+    let _ = writeln!(
+        text,
+        "Tweets when a new index has held for {} polls, at most every {}s, and not during the first {}s.",
+        config.debounce_readings,
+        config.tweet_interval_secs,
+        config.startup_quiet_secs
+    );
+    let _ = writeln!(text, "Earliest tweets:");
+    let mut allowed = now + secs(config.startup_quiet_secs);
+    let mut held = 0;
+    let tweets = polls.filter(|&poll| {
+        if poll < allowed {
+            return false;
+        }
+        held += 1;
+        if held < config.debounce_readings {
+            return false;
+        }
+        held = 0;
+        allowed = poll + secs(config.tweet_interval_secs);
+        true
+    });
+    for tweet in tweets.take(SCHEDULE_PREVIEW) {
+        let _ = writeln!(text, "  {}", time(tweet));
+    }
+    if let Some(hour) = config.daily_bar_hour {
+        let _ = writeln!(text, "Daily bar at {}", time(next_daily(now, hour)));
+    }
+    text
+}
+
+/// A random delay of 0 to `max_secs` seconds, whole seconds only.
+fn splay(max_secs: u64) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    // Hashers are seeded randomly, which is all the randomness this needs.
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    Duration::from_secs(random % (max_secs + 1))
+}
+
+/// Response of a geolocation service, see `Config::geo_endpoint`.
+#[derive(Debug, serde::Deserialize)]
+struct GeoResponse {
+    #[serde(rename = "countryCode")]
+    country_code: String,
+    #[serde(default)]
+    zip: Option<String>,
+}
+
+/// Picks the region this machine's public IP is in, or England if that
+/// can't be determined.
+async fn detect_region(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    geo_client: &reqwest::Client,
+    base_url: &str,
+    geo_endpoint: &str,
+) -> RegionId {
+    match locate(client, rate_limit, geo_client, base_url, geo_endpoint).await {
+        Ok(region) => {
+            log::info!("Detected region {:?}.", region);
+            region
+        }
+        Err(e) => {
+            log::warn!("Failed to detect the region, using England: {}", e);
+            RegionId::England
+        }
+    }
+}
+
+async fn locate(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    geo_client: &reqwest::Client,
+    base_url: &str,
+    geo_endpoint: &str,
+) -> Result<RegionId, Box<dyn std::error::Error>> {
+    let geo: GeoResponse = geo_client.get(geo_endpoint).send().await?.json().await?;
+    let outcode = outcode(&geo)?;
+    rate_limit.acquire().await;
+    let resp: RegionalResponse = client
+        .get(format!("{}/regional/postcode/{}", base_url, outcode))
+        .send()
+        .await?
+        .json()
+        .await?;
+    postcode_region(resp)
+}
+
+/// The outward part of the located postcode, which is all the API wants.
+fn outcode(geo: &GeoResponse) -> Result<&str, String> {
+    if geo.country_code != "GB" {
+        return Err(format!("Located outside the UK, in {}", geo.country_code));
+    }
+    geo.zip
+        .as_deref()
+        .and_then(|zip| zip.split_whitespace().next())
+        .ok_or_else(|| "No postcode for this location".to_string())
+}
+
+fn postcode_region(resp: RegionalResponse) -> Result<RegionId, Box<dyn std::error::Error>> {
+    match resp {
+        RegionalResponse::Data(d) => {
+            let item = d.into_iter().next().ok_or("No regions in response")?;
+            let id = item.regionid.ok_or("No region ID in response")?;
+            Ok(region_from_id(id)?)
+        }
+        RegionalResponse::Error(e) => Err(format!("{}: {}", e.code, e.message).into()),
+    }
+}
+
+/// Fetches the current reading for each of `regions`, in order.
+///
+/// Multiple regions are served from the bulk `/regional` endpoint where
+/// possible, falling back to individual requests if that fails.
+async fn fetch_regions(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    base_url: &str,
+    regions: &[RegionId],
+    max_concurrent: usize,
+    slot: SlotSpec,
+) -> Result<Vec<Reading>, Box<dyn std::error::Error>> {
+    if regions.len() > 1 {
+        match fetch_bulk(client, rate_limit, base_url, regions, slot).await {
+            Ok(readings) => return Ok(readings),
+            Err(e) => log::warn!(
+                "Bulk regional fetch failed, falling back to per-region requests: {}",
+                e
+            ),
+        }
+    }
+    fetch_bounded(regions, max_concurrent, |region| {
+        fetch_region(client, rate_limit, base_url, region, slot)
+    })
+    .await
+    .into_iter()
+    .collect()
+}
+
+/// Fetches `regions` from `api_base_url`, trying it as often as
+/// `api_failover_retry` allows before switching to `api_fallback_url`, if
+/// there is one. While `on_fallback`, the primary gets a single try on every
+/// poll, and is switched back to as soon as that works.
+async fn fetch_with_failover(
+    client: &reqwest::Client,
+    fallback_client: &reqwest::Client,
+    config: &Config,
+    shared: &Shared,
+    regions: &[RegionId],
+    on_fallback: &mut bool,
+) -> Result<Vec<Reading>, Box<dyn std::error::Error>> {
+    let (max_concurrent, slot) = (config.max_concurrent_requests, config.slot());
+    let rate_limit = &shared.api_rate_limit;
+    let primary = &config.api_base_url;
+    let Some(fallback) = &config.api_fallback_url else {
+        return fetch_regions(client, rate_limit, primary, regions, max_concurrent, slot).await;
+    };
+    let res = if *on_fallback {
+        fetch_regions(client, rate_limit, primary, regions, max_concurrent, slot).await
+    } else {
+        retry(
+            &config.api_failover_retry,
+            || fetch_regions(client, rate_limit, primary, regions, max_concurrent, slot),
+            |_| true,
+        )
+        .await
+    };
+    match res {
+        Ok(readings) => {
+            if std::mem::take(on_fallback) {
+                log::warn!("The API at {} is back, switching back to it.", primary);
+            }
+            return Ok(readings);
+        }
+        Err(e) if *on_fallback => log::debug!("The API at {} is still failing: {}", primary, e),
+        Err(e) => {
+            log::error!(
+                "The API at {} failed, failing over to {}: {}",
+                primary,
+                fallback,
+                e
+            );
+            *on_fallback = true;
+            shared
+                .counters
+                .failovers
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+    fetch_regions(
+        fallback_client,
+        rate_limit,
+        fallback,
+        regions,
+        max_concurrent,
+        slot,
+    )
+    .await
+}
+
+/// Runs `fetch` for every region concurrently, but with no more than
+/// `max_concurrent` of them in flight at once. Results are in input order.
+async fn fetch_bounded<T, E, F, Fut>(
+    regions: &[RegionId],
+    max_concurrent: usize,
+    fetch: F,
+) -> Vec<Result<T, E>>
+where
+    F: Fn(RegionId) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let semaphore = tokio::sync::Semaphore::new(max_concurrent.max(1));
+    let semaphore = &semaphore;
+    let fetch = &fetch;
+    futures_util::future::join_all(regions.iter().map(|&region| async move {
+        let _permit = semaphore
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        fetch(region).await
+    }))
+    .await
+}
+
+async fn fetch_region(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    base_url: &str,
+    region: RegionId,
+    slot: SlotSpec,
+) -> Result<Reading, Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now();
+    rate_limit.acquire().await;
+    let resp: RegionalResponse = if slot.offset == 0 {
+        client
+            .get(region_url(base_url, region))
+            .send()
+            .await?
+            .json()
+            .await?
+    } else {
+        let range = format!("{}/fw24h", now.format(carbon_date_format::FORMAT));
+        let url = region_range_url(base_url, region, &range);
+        match client.get(&url).send().await?.json().await? {
+            RegionalHistoryResponse::Data(item) => RegionalResponse::Data(vec![item]),
+            RegionalHistoryResponse::Error(e) => RegionalResponse::Error(e),
+        }
+    };
+    regional_reading(resp, region, slot, now)
+}
+
+/// Extracts the reading for `region` from a per-region response.
+fn regional_reading(
+    resp: RegionalResponse,
+    region: RegionId,
+    spec: SlotSpec,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Reading, Box<dyn std::error::Error>> {
+    match resp {
+        RegionalResponse::Data(d) => {
+            let item = d.into_iter().next().ok_or("No regions in response")?;
+            let spans: Vec<_> = item.data.iter().map(|s| (s.from, s.to)).collect();
+            let slot = &item.data[pick_slot(&spans, spec, now)?];
+            Ok(Reading {
+                region,
+                shortname: item.shortname,
+                from: slot.from,
+                to: slot.to,
+                intensity: slot.intensity,
+                is_estimated: slot.intensity.actual.is_none(),
+                raw_forecast: None,
+                generation_mix: slot.generationmix.clone(),
+            })
+        }
+        RegionalResponse::Error(e) => Err(format!("{}: {}", e.code, e.message).into()),
+    }
+}
+
+async fn fetch_bulk(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    base_url: &str,
+    regions: &[RegionId],
+    spec: SlotSpec,
+) -> Result<Vec<Reading>, Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now();
+    let url = if spec.offset == 0 {
+        format!("{}/regional", base_url)
+    } else {
+        format!(
+            "{}/regional/intensity/{}/fw24h",
+            base_url,
+            now.format(carbon_date_format::FORMAT)
+        )
+    };
+    rate_limit.acquire().await;
+    let resp: BulkRegionalResponse = client.get(&url).send().await?.json().await?;
+    let slot = match resp {
+        BulkRegionalResponse::Data(mut d) => {
+            let spans: Vec<_> = d.iter().map(|s| (s.from, s.to)).collect();
+            d.swap_remove(pick_slot(&spans, spec, now)?)
+        }
+        BulkRegionalResponse::Error(e) => return Err(format!("{}: {}", e.code, e.message).into()),
+    };
+    regions
+        .iter()
+        .map(|&region| {
+            let r = slot
+                .regions
+                .iter()
+                .find(|r| r.regionid == region as u16)
+                .ok_or_else(|| format!("Region {:?} missing from bulk response", region))?;
+            Ok(Reading {
+                region,
+                shortname: r.shortname.clone(),
+                from: slot.from,
+                to: slot.to,
+                intensity: r.intensity,
+                is_estimated: r.intensity.actual.is_none(),
+                raw_forecast: None,
+                generation_mix: r.generationmix.clone(),
+            })
+        })
+        .collect()
+}
+
+const STATE_TOPIC: &str = "carbon/intensity";
+const FORECAST_TOPIC: &str = "carbon/intensity/forecast";
+const AVAILABILITY_TOPIC: &str = "carbon/intensity/status";
+const HISTORY_TOPIC: &str = "carbon/intensity/history";
+const FORECAST_WINDOW_TOPIC: &str = "carbon/intensity/forecast_24h";
+const LEVEL_TOPIC: &str = "carbon/intensity/level";
+/// Unlike `AVAILABILITY_TOPIC`, which tracks the broker connection, this
+/// tracks whether polling the API works.
+const POLL_STATUS_TOPIC: &str = "carbon/intensity/available";
+const LINK_TOPIC: &str = "carbon/intensity/mqtt_link";
+const RENEWABLES_TOPIC: &str = "carbon/intensity/renewables_pct";
+const LOW_CARBON_TOPIC: &str = "carbon/intensity/low_carbon_pct";
+const SCORE_TOPIC: &str = "carbon/intensity/score";
+const DESCRIPTION_TOPIC: &str = "carbon/intensity/description";
+/// Seconds between the start of the published reading's slot and the
+/// publish, as `carbon_intensity_data_age_seconds`.
+const DATA_AGE_TOPIC: &str = "carbon/intensity/data_age_seconds";
+/// Only ever published to by `--selftest`.
+const SELFTEST_TOPIC: &str = "carbon/intensity/selftest";
+/// Any message here asks for the latest reading to be published right away.
+const GET_TOPIC: &str = "carbon/intensity/get";
+const FORECAST_UNIT: &str = "gCO2/kWh";
+
+/// Maps the topics above into an instance's own namespace, if it has one.
+#[derive(Debug, Clone, Default)]
+struct Topics {
+    instance: Option<String>,
+}
+
+impl Topics {
+    /// Inserts the instance after `STATE_TOPIC`, so that for example
+    /// `carbon/intensity/forecast` becomes `carbon/intensity/<instance>/forecast`.
+    fn get(&self, topic: &str) -> String {
+        match (&self.instance, topic.strip_prefix(STATE_TOPIC)) {
+            (Some(instance), Some(rest)) => format!("{}/{}{}", STATE_TOPIC, instance, rest),
+            _ => topic.to_string(),
+        }
+    }
+}
+
+/// A payload carrying its schema version, from `PayloadSchema::V2` on.
+#[derive(Debug, serde::Serialize)]
+struct Versioned<'a, T> {
+    payload_schema_version: u32,
+    #[serde(flatten)]
+    payload: &'a T,
+}
+
+/// The state as published from `PayloadSchema::V2` on: the reported
+/// reading, when it was polled, and whether it's outdated.
+#[derive(Debug, serde::Serialize)]
+struct StatePayload<'a> {
+    #[serde(flatten)]
+    reading: &'a Reading,
+    /// When the poll this came from finished, see `Snapshot::polled_at`.
+    fetched_at: chrono::DateTime<chrono::Utc>,
+    /// Whether the reading's slot ended more than `stale_after_secs` ago,
+    /// i.e. the API is serving old data.
+    stale: bool,
+    staleness_secs: i64,
+}
+
+/// The forecast window as published from `PayloadSchema::V2` on. It used to
+/// be a bare array, which has no room for a version.
+#[derive(Debug, serde::Serialize)]
+struct WindowPayload<'a> {
+    slots: &'a [WindowSlot],
+}
+
+/// A payload tagged with the instance that published it.
+#[derive(Debug, serde::Serialize)]
+struct Tagged<'a, T> {
+    instance: &'a str,
+    #[serde(flatten)]
+    payload: &'a T,
+}
+
+/// A message to publish, always at QoS 1.
+#[derive(Debug, Clone)]
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+    /// Whether the payload is JSON, which is sent as the content type over
+    /// MQTT 5.
+    json: bool,
+    /// Sent as user properties over MQTT 5, after the client's own.
+    properties: Vec<(String, String)>,
+}
+
+impl Message {
+    fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>) -> Self {
+        Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain: false,
+            json: false,
+            properties: Vec::new(),
+        }
+    }
+
+    fn json(topic: impl Into<String>, value: &impl serde::Serialize) -> serde_json::Result<Self> {
+        Ok(Message {
+            json: true,
+            ..Message::new(topic, serde_json::to_vec(value)?)
+        })
+    }
+
+    /// A JSON message in `schema`, which from `PayloadSchema::V2` on means
+    /// with the schema version added to `value`'s fields.
+    fn versioned(
+        topic: impl Into<String>,
+        value: &impl serde::Serialize,
+        schema: PayloadSchema,
+    ) -> serde_json::Result<Self> {
+        match schema {
+            PayloadSchema::V1 => Message::json(topic, value),
+            PayloadSchema::V2 => Message::json(
+                topic,
+                &Versioned {
+                    payload_schema_version: schema.version(),
+                    payload: value,
+                },
+            ),
+        }
+    }
+
+    fn retained(mut self) -> Self {
+        self.retain = true;
+        self
+    }
+
+    fn with_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// Something that takes messages to publish: the broker, or in tests, a
+/// recorder.
+trait MqttPublisher {
+    fn publish(&self, message: Message) -> futures_util::future::BoxFuture<'_, anyhow::Result<()>>;
+}
+
+impl MqttPublisher for MqttClient {
+    fn publish(&self, message: Message) -> futures_util::future::BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(MqttClient::publish(self, message))
+    }
+}
+
+/// An MQTT client speaking either protocol version.
+#[derive(Clone)]
+enum MqttClient {
+    V3(rumqttc::AsyncClient),
+    V5 {
+        client: rumqttc::v5::AsyncClient,
+        /// Attached to every publish.
+        user_properties: Vec<(String, String)>,
+    },
+}
+
+impl MqttClient {
+    async fn publish(&self, message: Message) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V3(client) => {
+                client
+                    .publish(
+                        message.topic,
+                        rumqttc::QoS::AtLeastOnce,
+                        message.retain,
+                        message.payload,
+                    )
+                    .await?
+            }
+            MqttClient::V5 {
+                client,
+                user_properties,
+            } => {
+                let properties = publish_properties(&message, user_properties);
+                client
+                    .publish_with_properties(
+                        message.topic,
+                        rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                        message.retain,
+                        message.payload,
+                        properties,
+                    )
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `publish`, but fails instead of waiting when the request queue is
+    /// full.
+    fn try_publish(&self, message: Message) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V3(client) => client.try_publish(
+                message.topic,
+                rumqttc::QoS::AtLeastOnce,
+                message.retain,
+                message.payload,
+            )?,
+            MqttClient::V5 {
+                client,
+                user_properties,
+            } => {
+                let properties = publish_properties(&message, user_properties);
+                client.try_publish_with_properties(
+                    message.topic,
+                    rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                    message.retain,
+                    message.payload,
+                    properties,
+                )?
+            }
+        }
+        Ok(())
+    }
+
+    fn try_subscribe(&self, topic: &str) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V3(client) => client.try_subscribe(topic, rumqttc::QoS::AtMostOnce)?,
+            MqttClient::V5 { client, .. } => {
+                client.try_subscribe(topic, rumqttc::v5::mqttbytes::QoS::AtMostOnce)?
+            }
+        }
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V3(client) => client.disconnect().await?,
+            MqttClient::V5 { client, .. } => client.disconnect().await?,
+        }
+        Ok(())
+    }
+}
+
+fn publish_properties(
+    message: &Message,
+    user_properties: &[(String, String)],
+) -> rumqttc::v5::mqttbytes::v5::PublishProperties {
+    rumqttc::v5::mqttbytes::v5::PublishProperties {
+        content_type: message.json.then(|| "application/json".to_string()),
+        user_properties: user_properties
+            .iter()
+            .chain(&message.properties)
+            .cloned()
+            .collect(),
+        ..Default::default()
+    }
+}
+
+enum MqttEventLoop {
+    V3(Box<rumqttc::EventLoop>),
+    V5(Box<rumqttc::v5::EventLoop>),
+}
+
+/// The MQTT events we act on, whatever the protocol version.
+#[derive(Debug)]
+enum MqttEvent {
+    Connected,
+    SubscribeFailed,
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+    },
+    /// We sent a disconnect.
+    Disconnected,
+    Other,
+}
+
+impl MqttEventLoop {
+    /// Drives the connection, reconnecting on the next call after an error.
+    async fn poll(&mut self) -> anyhow::Result<MqttEvent> {
+        use rumqttc::v5::mqttbytes::v5::{Packet as V5Packet, SubscribeReasonCode as V5Code};
+        use rumqttc::{Event, Outgoing, Packet, SubscribeReasonCode};
+
+        Ok(match self {
+            MqttEventLoop::V3(event_loop) => match event_loop.poll().await? {
+                Event::Incoming(Packet::ConnAck(_)) => MqttEvent::Connected,
+                Event::Incoming(Packet::SubAck(ack))
+                    if ack.return_codes.contains(&SubscribeReasonCode::Failure) =>
+                {
+                    MqttEvent::SubscribeFailed
+                }
+                Event::Incoming(Packet::Publish(publish)) => MqttEvent::Publish {
+                    topic: publish.topic,
+                    payload: publish.payload.to_vec(),
+                },
+                Event::Outgoing(Outgoing::Disconnect) => MqttEvent::Disconnected,
+                event => {
+                    log::trace!("MQTT event: {:?}", event);
+                    MqttEvent::Other
+                }
+            },
+            MqttEventLoop::V5(event_loop) => match event_loop.poll().await? {
+                rumqttc::v5::Event::Incoming(V5Packet::ConnAck(_)) => MqttEvent::Connected,
+                rumqttc::v5::Event::Incoming(V5Packet::SubAck(ack))
+                    if ack
+                        .return_codes
+                        .iter()
+                        .any(|code| !matches!(code, V5Code::Success(_))) =>
+                {
+                    MqttEvent::SubscribeFailed
+                }
+                rumqttc::v5::Event::Incoming(V5Packet::Publish(publish)) => MqttEvent::Publish {
+                    topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                    payload: publish.payload.to_vec(),
+                },
+                rumqttc::v5::Event::Outgoing(Outgoing::Disconnect) => MqttEvent::Disconnected,
+                event => {
+                    log::trace!("MQTT event: {:?}", event);
+                    MqttEvent::Other
+                }
+            },
+        })
+    }
+}
+
+/// The Home Assistant discovery topic and config for the forecast sensor.
+fn discovery_config(
+    ha: &HomeAssistantConfig,
+    unit: &str,
+    topics: &Topics,
+    region: RegionId,
+) -> (String, serde_json::Value) {
+    let object_id = format!("carbon_alert_{}", region as u16);
+    let topic = format!(
+        "{}/sensor/{}/forecast/config",
+        ha.discovery_prefix, object_id
+    );
+    let mut config = serde_json::json!({
+        "name": format!("Carbon intensity {:?}", region),
+        "unique_id": format!("{}_forecast", object_id),
+        "state_topic": topics.get(FORECAST_TOPIC),
+        "availability_topic": topics.get(AVAILABILITY_TOPIC),
+        "payload_available": "online",
+        "payload_not_available": "offline",
+        "unit_of_measurement": unit,
+        "state_class": "measurement",
+        "device": {
+            "identifiers": [object_id],
+            "name": "carbon-alert",
+        },
+    });
+    if let Some(device_class) = &ha.device_class {
+        config["device_class"] = device_class.clone().into();
+    }
+    (topic, config)
+}
+
+/// Waits for the next snapshot for the notifier `name`, or `None` once the
+/// channel has closed. The channel holds `None` until the first poll, and
+/// after a failed one, neither of which is anything to notify about.
+///
+/// The channel only keeps the latest snapshot, so a notifier that's busy
+/// while several come in only gets to see the last of them. That's fine for
+/// notifiers reporting the current state, but it means `debounce_readings`
+/// counts the readings a notifier saw rather than all polls. Notifiers that
+/// need every snapshot use `Delivery::Every` instead.
+///
+/// `last_seq` is the `seq` of the snapshot it saw last, used to log how many
+/// were skipped.
+async fn next_snapshot(
+    rx: &mut tokio::sync::watch::Receiver<Option<Snapshot>>,
+    name: &str,
+    last_seq: &mut u64,
+) -> Option<Snapshot> {
+    if rx.borrow().is_none() {
+        log::debug!("{} is waiting for a reading.", name);
+    }
+    loop {
+        rx.changed().await.ok()?;
+        let Some(snapshot) = rx.borrow().clone() else {
+            log::debug!("{} got no reading, still waiting.", name);
+            continue;
+        };
+        // A restarted notifier starts from 0 and hasn't missed anything.
+        let skipped = match *last_seq {
+            0 => 0,
+            last => snapshot.seq.saturating_sub(last + 1),
+        };
+        if skipped > 0 {
+            log::info!(
+                "{} coalesced {} readings that came in while it was busy.",
+                name,
+                skipped
+            );
+        }
+        *last_seq = snapshot.seq;
+        return Some(snapshot);
+    }
+}
+
+/// How many snapshots a `Delivery::Every` notifier may fall behind by before
+/// it starts missing them.
+const EVERY_SNAPSHOT_CAPACITY: usize = 32;
+
+/// The poller's end of the channels that notifiers get snapshots from.
+struct SnapshotTx {
+    /// The latest snapshot, or `None` after a failed poll.
+    latest: tokio::sync::watch::Sender<Option<Snapshot>>,
+    /// Every snapshot, for `Delivery::Every`. Failed polls aren't sent.
+    every: tokio::sync::broadcast::Sender<Snapshot>,
+}
+
+impl SnapshotTx {
+    fn new() -> (Self, tokio::sync::watch::Receiver<Option<Snapshot>>) {
+        let (latest, rx) = tokio::sync::watch::channel(None);
+        let (every, _) = tokio::sync::broadcast::channel(EVERY_SNAPSHOT_CAPACITY);
+        (SnapshotTx { latest, every }, rx)
+    }
+
+    /// Snapshots for a notifier wanting `delivery`, starting with the next
+    /// one sent.
+    fn subscribe(&self, delivery: Delivery) -> Snapshots {
+        match delivery {
+            Delivery::Latest => Snapshots::Latest(self.latest.subscribe()),
+            Delivery::Every => Snapshots::Every(self.every.subscribe(), self.latest.subscribe()),
+        }
+    }
+
+    fn send(&self, snapshot: Option<Snapshot>) {
+        if let Some(snapshot) = &snapshot {
+            // This only fails if nobody wants every snapshot.
+            let _ = self.every.send(snapshot.clone());
+        }
+        // Unlike `send`, this keeps the value around even if nobody is
+        // listening.
+        self.latest.send_replace(snapshot);
+    }
+
+    /// Whether the notifiers have all exited. Each of them holds on to a
+    /// receiver for the latest snapshot, whichever delivery it uses.
+    fn is_closed(&self) -> bool {
+        self.latest.is_closed()
+    }
+}
+
+/// Where a notifier gets its snapshots from, depending on its `Delivery`.
+enum Snapshots {
+    Latest(tokio::sync::watch::Receiver<Option<Snapshot>>),
+    /// With a receiver for the latest snapshot that's only there for
+    /// `SnapshotTx::is_closed`.
+    Every(
+        tokio::sync::broadcast::Receiver<Snapshot>,
+        tokio::sync::watch::Receiver<Option<Snapshot>>,
+    ),
+}
+
+impl Snapshots {
+    /// Another receiver of the same kind, for a restarted notifier. With
+    /// `Every`, it doesn't get anything sent before this was called.
+    fn resubscribe(&self) -> Self {
+        match self {
+            Snapshots::Latest(rx) => Snapshots::Latest(rx.clone()),
+            Snapshots::Every(rx, latest) => Snapshots::Every(rx.resubscribe(), latest.clone()),
+        }
+    }
+
+    /// The next snapshot to notify about, or `None` once polling has stopped.
+    async fn next(&mut self, name: &str, last_seq: &mut u64) -> Option<Snapshot> {
+        match self {
+            Snapshots::Latest(rx) => next_snapshot(rx, name, last_seq).await,
+            Snapshots::Every(rx, _) => next_event(rx, name).await,
+        }
+    }
+}
+
+/// Waits for the next snapshot with `Delivery::Every`. Once a notifier is
+/// more than `EVERY_SNAPSHOT_CAPACITY` snapshots behind, the oldest ones are
+/// dropped. That's logged, and it carries on from the oldest one left.
+async fn next_event(
+    rx: &mut tokio::sync::broadcast::Receiver<Snapshot>,
+    name: &str,
+) -> Option<Snapshot> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    loop {
+        match rx.recv().await {
+            Ok(snapshot) => return Some(snapshot),
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("{} fell behind and missed {} readings.", name, missed);
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+async fn run_mqtt(
+    config: Config,
+    intensity_rx: tokio::sync::watch::Receiver<Option<Snapshot>>,
+    mut snapshots: Snapshots,
+) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
+    let topics = config.topics();
+    let instance = config.instance_name();
+    let region = config.primary_region().map_err(anyhow::Error::msg)?;
+    log::info!("Publishing as instance {}.", instance);
+    let mut session = SessionSetup {
+        subscriptions: vec![topics.get(STATE_TOPIC), topics.get(GET_TOPIC)],
+        announcements: Vec::new(),
+        delay: Duration::from_millis(config.mqtt.post_connect_delay_ms),
+        link_topic: topics.get(&config.mqtt.link_topic),
+        fail_fast: config.fail_fast,
+    };
+    let mut last_will = None;
+    if let Some(ha) = &config.mqtt.home_assistant {
+        last_will = Some(Message::new(topics.get(AVAILABILITY_TOPIC), "offline").retained());
+        let (topic, discovery) = discovery_config(ha, &config.mqtt.forecast_unit, &topics, region);
+        session.announcements.push(
+            Message::json(topic, &discovery)
+                .map_err(anyhow::Error::msg)?
+                .retained(),
+        );
+        session
+            .announcements
+            .push(Message::new(topics.get(AVAILABILITY_TOPIC), "online").retained());
+    }
+
+    let (client, event_loop) = config
+        .mqtt
+        .client("mqtt", last_will, region, &instance)
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    // A restarted task brings its own connection, so this one has to go.
+    let mut driver = AbortOnDrop(tokio::task::spawn(drive_event_loop(
+        event_loop,
+        client.clone(),
+        session,
+        topics.clone(),
+        config.mqtt.encoding(),
+        config.mqtt.stale_after(),
+        intensity_rx.clone(),
+    )));
+    let status = AbortOnDrop(tokio::task::spawn({
+        let client = client.clone();
+        let topic = topics.get(&config.mqtt.poll_status_topic);
+        let (expect_window, rx) = (config.mqtt.forecast_window, intensity_rx.clone());
+        async move { publish_poll_status(&client, topic, expect_window, rx).await }
+    }));
+    let mut filter = PublishFilter::new(config.mqtt.publish_mode);
+    let mut last_seq = 0;
+    loop {
+        let snapshot = tokio::select! {
+            snapshot = snapshots.next("MQTT", &mut last_seq) => snapshot,
+            // Before we disconnect, the driver only stops on a fail-fast
+            // error.
+            res = &mut driver.0 => {
+                let e = match res {
+                    Ok(Ok(())) => anyhow::Error::msg("MQTT event loop stopped"),
+                    Ok(Err(e)) => e,
+                    Err(e) => e.into(),
+                };
+                return Err(e.into());
+            }
+        };
+        let Some(snapshot) = snapshot else {
+            break;
+        };
+        publish_if_due(
+            &client,
+            &config,
+            &topics,
+            &mut filter,
+            &snapshot,
+            chrono::Utc::now(),
+        )
+        .await?;
+    }
+
+    // The channel only closes on shutdown, so say goodbye properly.
+    drop(status);
+    say_goodbye(&client, &config, &topics).await?;
+    // The disconnect is queued behind everything else, so once the event
+    // loop has sent it, it's done.
+    let _ = (&mut driver.0).await;
+    Ok(())
+}
+
+/// Publishes `snapshot` unless it's stale and `suppress_stale_publish` is
+/// set, or `filter` says there's nothing new. Returns whether it did.
+async fn publish_if_due(
+    publisher: &impl MqttPublisher,
+    config: &Config,
+    topics: &Topics,
+    filter: &mut PublishFilter,
+    snapshot: &Snapshot,
+    now: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<bool> {
+    let stale_after = config.mqtt.stale_after();
+    if config.mqtt.suppress_stale_publish && snapshot.is_stale(now, stale_after) {
+        log::warn!(
+            "The reading for {} is over {:?} old, not publishing.",
+            snapshot.reported().from,
+            stale_after
+        );
+        return Ok(false);
+    }
+    if !filter.should_publish(snapshot) {
+        log::debug!("Nothing changed, not publishing.");
+        return Ok(false);
+    }
+    publish_snapshot(publisher, config, topics, snapshot).await?;
+    Ok(true)
+}
+
+/// Publishes everything there is to know about `snapshot`.
+async fn publish_snapshot(
+    publisher: &impl MqttPublisher,
+    config: &Config,
+    topics: &Topics,
+    snapshot: &Snapshot,
+) -> anyhow::Result<()> {
+    let intensity = snapshot.reported().intensity;
+    // How far into (or past) the reading's slot we are, which is as
+    // fresh as the data can be.
+    let age = (chrono::Utc::now() - snapshot.reported().from).num_seconds();
+    log::info!(
+        "Publishing: {:?}, carbon_intensity_data_age_seconds={}",
+        intensity,
+        age
+    );
+    publisher
+        .publish(
+            state_message(
+                snapshot,
+                topics,
+                config.mqtt.encoding(),
+                config.mqtt.stale_after(),
+                chrono::Utc::now(),
+            )
+            .map_err(anyhow::Error::msg)?,
+        )
+        .await?;
+    publisher
+        .publish(level_message(snapshot, topics, &config.mqtt.level_topic))
+        .await?;
+    let mix = &snapshot.reported().generation_mix;
+    if !mix.is_empty() {
+        let shares = [
+            (RENEWABLES_TOPIC, fuel_share(mix, &config.renewable_fuels)),
+            (
+                LOW_CARBON_TOPIC,
+                low_carbon_share(mix, &config.renewable_fuels),
+            ),
+        ];
+        for (topic, share) in shares {
+            publisher
+                .publish(Message::new(topics.get(topic), format!("{:.1}", share)).retained())
+                .await?;
+        }
+    }
+    if let Some(max) = config.score_max {
+        let score = greenness_score(snapshot.reported().intensity.forecast, max);
+        publisher
+            .publish(Message::new(topics.get(SCORE_TOPIC), score.to_string()).retained())
+            .await?;
+    }
+    if config.describe_index {
+        let description = config.description(intensity.index);
+        publisher
+            .publish(Message::new(topics.get(DESCRIPTION_TOPIC), description).retained())
+            .await?;
+    }
+    publisher
+        .publish(Message::new(topics.get(DATA_AGE_TOPIC), age.to_string()))
+        .await?;
+    if config.mqtt.home_assistant.is_some() {
+        let forecast =
+            format_forecast(snapshot.reported_forecast(), config.mqtt.forecast_precision);
+        publisher
+            .publish(
+                Message::new(topics.get(FORECAST_TOPIC), forecast)
+                    .retained()
+                    .with_property("unit", &config.mqtt.forecast_unit),
+            )
+            .await?;
+    }
+    for reading in &snapshot.others {
+        let topic = topics.get(&format!("{}/{}", STATE_TOPIC, reading.region as u16));
+        publisher
+            .publish(
+                reading_message(topic, reading, config.mqtt.encoding())
+                    .map_err(anyhow::Error::msg)?,
+            )
+            .await?;
+    }
+    if let Some(window) = &snapshot.window {
+        let slots: Vec<WindowSlot> = window.iter().map(WindowSlot::from).collect();
+        publisher
+            .publish(
+                window_message(
+                    topics.get(&config.mqtt.forecast_window_topic),
+                    &slots,
+                    config.mqtt.payload_schema,
+                )
+                .map_err(anyhow::Error::msg)?,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Marks everything we publish as offline, then queues the disconnect.
+async fn say_goodbye(client: &MqttClient, config: &Config, topics: &Topics) -> anyhow::Result<()> {
+    client
+        .publish(LinkState::Disconnected.message(&topics.get(&config.mqtt.link_topic)))
+        .await?;
+    client
+        .publish(
+            Message::new(
+                topics.get(&config.mqtt.poll_status_topic),
+                PollStatus::Offline.as_str(),
+            )
+            .retained(),
+        )
+        .await?;
+    if config.mqtt.home_assistant.is_some() {
+        client
+            .publish(Message::new(topics.get(AVAILABILITY_TOPIC), "offline").retained())
+            .await?;
+    }
+    client.disconnect().await
+}
+
+/// The poller's view of the API, as published on `POLL_STATUS_TOPIC`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PollStatus {
+    /// The last poll got everything it asked for.
+    Online,
+    /// The last poll got a reading, but an outdated one, or without the
+    /// forecast window it was meant to fetch.
+    Degraded,
+    /// The last poll failed, or polling has stopped.
+    Offline,
+}
+
+impl PollStatus {
+    /// The status after a poll that got `snapshot`, or failed if that's
+    /// `None`.
+    fn of(
+        snapshot: Option<&Snapshot>,
+        expect_window: bool,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Self {
+        match snapshot {
+            None => PollStatus::Offline,
+            Some(s) if s.primary.to <= now || (expect_window && s.window.is_none()) => {
+                PollStatus::Degraded
+            }
+            Some(_) => PollStatus::Online,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            PollStatus::Online => "online",
+            PollStatus::Degraded => "degraded",
+            PollStatus::Offline => "offline",
+        }
+    }
+}
+
+/// Publishes the `PollStatus` to `topic` whenever it changes, until the
+/// channel closes.
+async fn publish_poll_status(
+    publisher: &impl MqttPublisher,
+    topic: String,
+    expect_window: bool,
+    mut rx: tokio::sync::watch::Receiver<Option<Snapshot>>,
+) {
+    let mut published = None;
+    loop {
+        let status = {
+            let latest = rx.borrow_and_update();
+            PollStatus::of(latest.as_ref(), expect_window, chrono::Utc::now())
+        };
+        // Before the first poll, there's no view to publish.
+        let waiting = status == PollStatus::Offline && published.is_none();
+        if !waiting && published != Some(status) {
+            let message = Message::new(topic.clone(), status.as_str()).retained();
+            match publisher.publish(message).await {
+                Ok(()) => published = Some(status),
+                Err(e) => log::warn!("Failed to publish the poll status: {}", e),
+            }
+        }
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A slot of the forecast window as published.
+#[derive(Debug, serde::Serialize)]
+struct WindowSlot {
+    #[serde(with = "carbon_date_format")]
+    from: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "carbon_date_format")]
+    to: chrono::DateTime<chrono::Utc>,
+    index: Intensity,
+    forecast: GramsPerKwh,
+}
+
+impl From<&Reading> for WindowSlot {
+    fn from(reading: &Reading) -> Self {
+        WindowSlot {
+            from: reading.from,
+            to: reading.to,
+            index: reading.intensity.index,
+            forecast: reading.intensity.forecast,
+        }
+    }
+}
+
+fn window_message(
+    topic: String,
+    slots: &[WindowSlot],
+    schema: PayloadSchema,
+) -> serde_json::Result<Message> {
+    let message = match schema {
+        PayloadSchema::V1 => Message::json(topic, &slots)?,
+        PayloadSchema::V2 => Message::versioned(topic, &WindowPayload { slots }, schema)?,
+    };
+    Ok(message.retained())
+}
+
+/// The message for `STATE_TOPIC`, carrying the reported reading.
+fn state_message(
+    snapshot: &Snapshot,
+    topics: &Topics,
+    encoding: Encoding,
+    stale_after: Duration,
+    now: chrono::DateTime<chrono::Utc>,
+) -> serde_json::Result<Message> {
+    let topic = topics.get(STATE_TOPIC);
+    match encoding {
+        Encoding {
+            format: PayloadFormat::Json,
+            schema: PayloadSchema::V2,
+        } => Message::versioned(
+            topic,
+            &StatePayload {
+                reading: snapshot.reported(),
+                fetched_at: snapshot.polled_at,
+                stale: snapshot.is_stale(now, stale_after),
+                staleness_secs: snapshot.staleness_secs(now),
+            },
+            encoding.schema,
+        ),
+        _ => reading_message(topic, snapshot.reported(), encoding),
+    }
+}
+
+/// The message for the level topic, carrying the reported index as a number.
+fn level_message(snapshot: &Snapshot, topics: &Topics, topic: &str) -> Message {
+    let level = snapshot.reported().intensity.index as u8;
+    Message::new(topics.get(topic), level.to_string()).retained()
+}
+
+fn reading_message(
+    topic: String,
+    reading: &Reading,
+    encoding: Encoding,
+) -> serde_json::Result<Message> {
+    Ok(match encoding.format {
+        PayloadFormat::Index => Message::new(topic, [reading.intensity.index as u8]),
+        PayloadFormat::Json => Message::versioned(topic, reading, encoding.schema)?,
+        PayloadFormat::Compact => Message::new(topic, compact_payload(reading)),
+    })
+}
+
+/// Formats a forecast with `precision` decimal places, or if that's not set,
+/// as an integer if it is one and with as many places as needed otherwise.
+fn format_forecast(forecast: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) => format!("{:.*}", precision, forecast),
+        None => forecast.to_string(),
+    }
+}
+
+/// What to set up on the broker every time a connection is made. We use clean
+/// sessions, so subscriptions don't survive a reconnect.
+#[derive(Debug)]
+struct SessionSetup {
+    subscriptions: Vec<String>,
+    announcements: Vec<Message>,
+    /// See `MQTTConnectionConfig::post_connect_delay_ms`.
+    delay: Duration,
+    /// Where the state of the connection goes, see `LinkState`.
+    link_topic: String,
+    /// Whether to give up on the first connection error.
+    fail_fast: bool,
+}
+
+impl SessionSetup {
+    /// Queues the subscriptions and announcements. This must not block, as
+    /// it's called from the loop that drains the request queue.
+    fn apply(&self, client: &MqttClient) -> anyhow::Result<()> {
+        for topic in &self.subscriptions {
+            client.try_subscribe(topic)?;
+        }
+        for message in &self.announcements {
+            client.try_publish(message.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// Polls the MQTT event loop until we disconnect, reconnecting with
+/// exponential backoff whenever the connection drops or can't be established
+/// in time. Requests on `GET_TOPIC` are answered from `latest`. With
+/// `session.fail_fast`, the first connection error is returned instead.
+async fn drive_event_loop(
+    mut event_loop: MqttEventLoop,
+    client: MqttClient,
+    session: SessionSetup,
+    topics: Topics,
+    encoding: Encoding,
+    stale_after: Duration,
+    latest: tokio::sync::watch::Receiver<Option<Snapshot>>,
+) -> anyhow::Result<()> {
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    let mut link = LinkState::Disconnected;
+    let mut needs_setup = false;
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        if link == LinkState::Connected && needs_setup {
+            // On failure, this is retried on the next turn of the loop, which
+            // comes around at least once per keep-alive interval.
+            match session.apply(&client) {
+                Ok(()) => needs_setup = false,
+                Err(e) => log::warn!("Failed to set up MQTT session, will retry: {}", e),
+            }
+        }
+        // The connection timeout covers the TCP and TLS handshakes too, and
+        // surfaces as an error here.
+        match event_loop.poll().await {
+            Ok(MqttEvent::Connected) => {
+                log::info!("Connected to MQTT broker.");
+                if !session.delay.is_zero() {
+                    // Nothing queued goes out until the event loop is polled
+                    // again, publishes from `run_mqtt` included.
+                    log::debug!("Waiting {:?} for the session to settle.", session.delay);
+                    tokio::time::sleep(session.delay).await;
+                }
+                link.change_to(LinkState::Connected, &client, &session.link_topic);
+                needs_setup = true;
+                backoff = MIN_BACKOFF;
+            }
+            Ok(MqttEvent::SubscribeFailed) => {
+                log::warn!("Broker rejected a subscription.");
+            }
+            Ok(MqttEvent::Disconnected) => {
+                // `run_mqtt` has published this already, while it still could.
+                log::info!("mqtt_link_state={}", LinkState::Disconnected.as_str());
+                return Ok(());
+            }
+            Ok(MqttEvent::Publish { topic, payload }) => match route_incoming(&topic, &topics) {
+                Incoming::GetLatest => {
+                    answer_get_latest(&client, &topics, encoding, stale_after, &latest)
+                }
+                Incoming::Echo => log::trace!("Ignoring echo on {}.", topic),
+                Incoming::Unknown => log::debug!(
+                    "Ignoring publish on {}: {:?}",
+                    topic,
+                    String::from_utf8_lossy(&payload)
+                ),
+            },
+            Ok(_) => {}
+            Err(e) if session.fail_fast => return Err(e.context("MQTT connection error")),
+            Err(e) => {
+                log::warn!("MQTT connection error, retrying in {:?}: {}", backoff, e);
+                link.change_to(LinkState::Reconnecting, &client, &session.link_topic);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// The state of the broker connection, as published on `LINK_TOPIC`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkState {
+    Connected,
+    /// The connection dropped or couldn't be established, and is being
+    /// retried with backoff.
+    Reconnecting,
+    /// Not connected yet, or disconnected on shutdown.
+    Disconnected,
+}
+
+impl LinkState {
+    /// Moves to `state`, logging and publishing to `topic` if that's a
+    /// change. While the link is down, the publish is queued and goes out
+    /// once it's back, just before the next change.
+    fn change_to(&mut self, state: LinkState, client: &MqttClient, topic: &str) {
+        if std::mem::replace(self, state) == state {
+            return;
+        }
+        log::info!("mqtt_link_state={}", state.as_str());
+        if let Err(e) = client.try_publish(state.message(topic)) {
+            log::warn!("Failed to publish the MQTT link state: {}", e);
+        }
+    }
+
+    fn message(self, topic: &str) -> Message {
+        Message::new(topic, self.as_str()).retained()
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            LinkState::Connected => "connected",
+            LinkState::Reconnecting => "reconnecting",
+            LinkState::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// What an incoming publish is for.
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    /// A request on `GET_TOPIC`.
+    GetLatest,
+    /// One of our own publishes coming back to us.
+    Echo,
+    Unknown,
+}
+
+fn route_incoming(topic: &str, topics: &Topics) -> Incoming {
+    if topic == topics.get(GET_TOPIC) {
+        Incoming::GetLatest
+    } else if topic == topics.get(STATE_TOPIC) {
+        Incoming::Echo
+    } else {
+        Incoming::Unknown
+    }
+}
+
+/// Publishes the latest reading, if there is one yet. Like the session setup,
+/// this mustn't block the event loop.
+fn answer_get_latest(
+    client: &MqttClient,
+    topics: &Topics,
+    encoding: Encoding,
+    stale_after: Duration,
+    latest: &tokio::sync::watch::Receiver<Option<Snapshot>>,
+) {
+    let message = latest
+        .borrow()
+        .as_ref()
+        .map(|snapshot| state_message(snapshot, topics, encoding, stale_after, chrono::Utc::now()));
+    match message {
+        Some(message) => {
+            let res = message
+                .map_err(anyhow::Error::from)
+                .and_then(|message| client.try_publish(message));
+            if let Err(e) = res {
+                log::warn!("Failed to answer request for the latest reading: {}", e);
+            }
+        }
+        None => log::info!("Latest reading requested, but there is none yet."),
+    }
+}
+
+/// Runs each of the checks for `--selftest`, printing how they went. Fails
+/// if any of them did.
+async fn selftest(config: &Config, shared: &Shared) -> Result<(), Box<dyn std::error::Error>> {
+    // The config has been parsed and validated by the time we get here.
+    println!("[ok] Config");
+    let mut failures = 0;
+    let mut report = |name: &str, res: anyhow::Result<String>| match res {
+        Ok(detail) => println!("[ok] {}: {}", name, detail),
+        Err(e) => {
+            failures += 1;
+            println!("[FAILED] {}: {:#}", name, e);
+        }
+    };
+
+    let api = async {
+        let client = config.http_client()?;
+        let readings = fetch_regions(
+            &client,
+            &shared.api_rate_limit,
+            &config.api_base_url,
+            &config.monitored_regions().map_err(anyhow::Error::msg)?,
+            config.max_concurrent_requests,
+            config.slot(),
+        )
+        .await
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        Ok::<_, anyhow::Error>(
+            readings
+                .iter()
+                .map(|r| format!("{} is {:?}", r.shortname, r.intensity.index))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    };
+    report("Carbon Intensity API", api.await);
+    report("MQTT", selftest_mqtt(config).await);
+    let twitter = async {
+        let user = egg_mode::auth::verify_tokens(&twitter_token(config)).await?;
+        Ok::<_, anyhow::Error>(format!("authenticated as @{}", user.screen_name))
+    };
+    if config.twitter_enabled {
+        report("Twitter", twitter.await);
+    }
+    if let Some(bluesky) = Bluesky::new(config, config.plain_http_client()?) {
+        let session = bluesky.create_session().await;
+        report(
+            "Bluesky",
+            session
+                .map(|s| format!("authenticated as @{}", s.handle))
+                .map_err(anyhow::Error::new),
+        );
+    }
+
+    if failures > 0 {
+        return Err(format!("{} self-test checks failed", failures).into());
+    }
+    Ok(())
+}
+
+/// Connects to the broker and publishes to `SELFTEST_TOPIC`, waiting until
+/// that has gone out.
+async fn selftest_mqtt(config: &Config) -> anyhow::Result<String> {
+    let instance = config.instance_name();
+    let (client, mut event_loop) = config
+        .mqtt
+        .client(
+            "mqtt-selftest",
+            None,
+            config.primary_region().map_err(anyhow::Error::msg)?,
+            &instance,
+        )
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    let topic = config.topics().get(SELFTEST_TOPIC);
+    let driver = async move {
+        loop {
+            if let MqttEvent::Disconnected = event_loop.poll().await? {
+                return Ok::<_, anyhow::Error>(());
+            }
+        }
+    };
+    let publish = async {
+        client
+            .publish(Message::new(&topic, format!("selftest from {}", instance)))
+            .await?;
+        client.disconnect().await
+    };
+    let timeout = Duration::from_secs(config.mqtt.connect_timeout_secs + 5);
+    tokio::time::timeout(timeout, async {
+        let (driven, published) = tokio::join!(driver, publish);
+        driven.and(published)
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Timed out after {:?}", timeout))??;
+    Ok(format!("published to {}", topic))
+}
+
+/// Fetches the last `hours` of readings for the primary region and publishes
+/// them to `HISTORY_TOPIC` in chronological order.
+async fn backfill(
+    config: &Config,
+    shared: &Shared,
+    hours: u32,
+    delay: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now();
+    let mut readings = fetch_history(
+        &config.http_client()?,
+        &shared.api_rate_limit,
+        &config.api_base_url,
+        config.primary_region()?,
+        now - chrono::Duration::hours(hours.into()),
+        now,
+    )
+    .await?;
+    readings.retain(|r| r.from < now);
+    readings.sort_by_key(|r| r.from);
+    log::info!("Backfilling {} readings.", readings.len());
+
+    // Uses a distinct client ID so as not to kick a running daemon off the
+    // broker.
+    let instance = config.instance_name();
+    let (client, mut event_loop) =
+        config
+            .mqtt
+            .client("mqtt-backfill", None, config.primary_region()?, &instance)?;
+    let history_topic = config.topics().get(HISTORY_TOPIC);
+    let driver = tokio::task::spawn(async move {
+        loop {
+            if let MqttEvent::Disconnected = event_loop.poll().await? {
+                return Ok(());
+            }
+        }
+    });
+    for reading in &readings {
+        log::debug!("Backfilling {}: {:?}", reading.from, reading.intensity);
+        client
+            .publish(Message::versioned(
+                &history_topic,
+                &Tagged {
+                    instance: &instance,
+                    payload: reading,
+                },
+                config.mqtt.payload_schema,
+            )?)
+            .await?;
+        tokio::time::sleep(delay).await;
+    }
+    // The disconnect is queued behind the publishes, so once it has gone out
+    // so have they.
+    client.disconnect().await?;
+    let res: anyhow::Result<()> = driver.await?;
+    res?;
+    log::info!("Backfill complete.");
+    Ok(())
+}
+
+async fn fetch_history(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    base_url: &str,
+    region: RegionId,
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<Reading>, Box<dyn std::error::Error>> {
+    let range = format!(
+        "{}/{}",
+        from.format(carbon_date_format::FORMAT),
+        to.format(carbon_date_format::FORMAT)
+    );
+    let url = region_range_url(base_url, region, &range);
+    rate_limit.acquire().await;
+    let resp: RegionalHistoryResponse = client.get(&url).send().await?.json().await?;
+    history_readings(resp, region)
+}
+
+/// Fetches the forecast for `region` for the 24 hours from `from`.
+async fn fetch_forecast_window(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    base_url: &str,
+    region: RegionId,
+    from: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<Reading>, Box<dyn std::error::Error>> {
+    let range = format!("{}/fw24h", from.format(carbon_date_format::FORMAT));
+    let url = region_range_url(base_url, region, &range);
+    rate_limit.acquire().await;
+    let resp: RegionalHistoryResponse = client.get(&url).send().await?.json().await?;
+    history_readings(resp, region)
+}
+
+/// Extracts every slot for `region` from a response covering a time range.
+fn history_readings(
+    resp: RegionalHistoryResponse,
+    region: RegionId,
+) -> Result<Vec<Reading>, Box<dyn std::error::Error>> {
+    match resp {
+        RegionalHistoryResponse::Data(item) => Ok(item
+            .data
+            .into_iter()
+            .map(|slot| Reading {
+                region,
+                shortname: item.shortname.clone(),
+                from: slot.from,
+                to: slot.to,
+                intensity: slot.intensity,
+                is_estimated: slot.intensity.actual.is_none(),
+                raw_forecast: None,
+                generation_mix: slot.generationmix,
+            })
+            .collect()),
+        RegionalHistoryResponse::Error(e) => Err(format!("{}: {}", e.code, e.message).into()),
+    }
+}
+
+/// Fetches national statistics between `from` and `to`, either for the whole
+/// period or, with `block_hours`, for each block of that many hours.
+async fn fetch_stats(
+    client: &reqwest::Client,
+    rate_limit: &RateLimiter,
+    base_url: &str,
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+    block_hours: Option<u32>,
+) -> Result<Vec<StatsItemResponse>, Box<dyn std::error::Error>> {
+    let mut url = format!(
+        "{}/intensity/stats/{}/{}",
+        base_url,
+        from.format(carbon_date_format::FORMAT),
+        to.format(carbon_date_format::FORMAT),
+    );
+    if let Some(block) = block_hours {
+        url = format!("{}/{}", url, block);
+    }
+    rate_limit.acquire().await;
+    let resp: StatsResponse = client.get(&url).send().await?.json().await?;
+    match resp {
+        StatsResponse::Data(d) => Ok(d),
+        StatsResponse::Error(e) => Err(format!("{}: {}", e.code, e.message).into()),
+    }
+}
+
+const STATS_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The national average over the past week, fetched on first use and then
+/// at most once every `STATS_REFRESH`.
+#[derive(Debug, Default)]
+struct WeeklyAverage {
+    average: Option<GramsPerKwh>,
+    fetched: Option<Instant>,
+}
+
+impl WeeklyAverage {
+    /// Returns the cached average, refreshing it first if it's stale. A failed
+    /// refresh keeps the previous value, if any, and is tried again next time.
+    async fn get(
+        &mut self,
+        client: &reqwest::Client,
+        rate_limit: &RateLimiter,
+        base_url: &str,
+    ) -> Option<GramsPerKwh> {
+        if !matches!(self.fetched, Some(t) if t.elapsed() < STATS_REFRESH) {
+            let to = chrono::Utc::now();
+            let from = to - chrono::Duration::days(7);
+            match fetch_stats(client, rate_limit, base_url, from, to, None).await {
+                Ok(stats) => match stats.first() {
+                    Some(item) => {
+                        log::debug!(
+                            "National average from {} to {}: {}.",
+                            item.from,
+                            item.to,
+                            item.intensity.average
+                        );
+                        self.average = Some(item.intensity.average);
+                        self.fetched = Some(Instant::now());
+                    }
+                    None => log::warn!("No weekly statistics in response."),
+                },
+                Err(e) => log::warn!("Failed to fetch weekly statistics: {}", e),
+            }
+        }
+        self.average
+    }
+}
+
+async fn run_tweeter(
+    config: Config,
+    mut snapshots: Snapshots,
+    shared: std::sync::Arc<Shared>,
+) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
+    let mut alerts = AlertState::from_config(&config, &config.twitter, Instant::now())
+        .with_buffer_size(shared.buffer_sizes.rate_alert.clone());
+    let mut comparison =
+        RegionComparison::new(config.compare_regions.clone(), config.compare_margin);
+    let mut thread = Thread::default();
+    let client = config
+        .http_client()
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+    let mut weekly_average = WeeklyAverage::default();
+    let mut last_seq = 0;
+    while let Some(snapshot) = snapshots.next("Twitter", &mut last_seq).await {
+        let reading = snapshot.notified();
+        let mut texts = Vec::new();
+        if let Some(alert) = alerts.decide(&reading.intensity, Instant::now()) {
+            let average = if config.compare_weekly_average {
+                weekly_average
+                    .get(&client, &shared.api_rate_limit, &config.api_base_url)
+                    .await
+            } else {
+                None
+            };
+            let ctx = RenderCtx {
+                staleness_secs: Some(snapshot.staleness_secs(chrono::Utc::now())),
+                hashtags: config.tweet_tags(reading.intensity.index),
+                ..notification_ctx(&config, reading, average)
+            };
+            texts.push(tweet_text(reading, &alert, ctx));
+        }
+        texts.extend(comparison.check(&snapshot).iter().map(Greener::to_string));
+        let mut queue = std::collections::VecDeque::from(texts);
+        while let Some(text) = queue.pop_front() {
+            let today = chrono::Utc::now().date().naive_utc();
+            let parent = thread.parent(today).filter(|_| config.thread_daily);
+            let retryable = |e: &egg_mode::error::Error| {
+                !matches!(e, egg_mode::error::Error::RateLimit(_)) && !replied_to_deleted(e)
+            };
+            let mut res = retry(
+                &config.notifier_retry,
+                || tweet(&config, &shared.counters, &text, parent),
+                retryable,
+            )
+            .await;
+            if matches!(&res, Err(e) if parent.is_some() && replied_to_deleted(e)) {
+                log::warn!("The tweet to reply to has been deleted, starting a new thread.");
+                res = retry(
+                    &config.notifier_retry,
+                    || tweet(&config, &shared.counters, &text, None),
+                    retryable,
+                )
+                .await;
+            }
+            match res {
+                Ok(post) => thread.posted(today, post.id),
+                Err(e) if config.fail_fast => return Err(Box::new(e)),
+                // Rather than failing and being restarted straight into
+                // the same limit, sit it out and try again. The alert state
+                // already counts this tweet as sent, so it can't be dropped.
+                Err(egg_mode::error::Error::RateLimit(reset)) => {
+                    let pause = rate_limit_pause(reset, chrono::Utc::now());
+                    log::warn!(
+                        "Twitter rate limit reached, not posting again until {} ({:?} from now).",
+                        chrono::TimeZone::timestamp_opt(&chrono::Utc, reset.into(), 0)
+                            .single()
+                            .map_or_else(|| reset.to_string(), |t| t.to_rfc3339()),
+                        pause
+                    );
+                    tokio::time::sleep(pause).await;
+                    queue.push_front(text);
+                    continue;
+                }
+                Err(e) => {
+                    dead_letter(
+                        &shared.counters,
+                        config.dead_letter_file.as_deref(),
+                        "twitter",
+                        &text,
+                        &e.to_string(),
+                    )
+                    .await;
+                    continue;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(config.tweet_interval_secs)).await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_bluesky(
+    config: Config,
+    mut snapshots: Snapshots,
+    shared: std::sync::Arc<Shared>,
+) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
+    let client = config
+        .http_client()
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+    let bluesky_client = config
+        .plain_http_client()
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+    let Some(bluesky) = Bluesky::new(&config, bluesky_client) else {
+        return Ok(());
+    };
+    let mut alerts = AlertState::from_config(&config, &config.bluesky, Instant::now())
+        .with_buffer_size(shared.buffer_sizes.rate_alert.clone());
+    let mut comparison =
+        RegionComparison::new(config.compare_regions.clone(), config.compare_margin);
+    let mut weekly_average = WeeklyAverage::default();
+    let mut last_seq = 0;
+    while let Some(snapshot) = snapshots.next("Bluesky", &mut last_seq).await {
+        let reading = snapshot.notified();
+        let mut texts = Vec::new();
+        if let Some(alert) = alerts.decide(&reading.intensity, Instant::now()) {
+            let average = if config.compare_weekly_average {
+                weekly_average
+                    .get(&client, &shared.api_rate_limit, &config.api_base_url)
+                    .await
+            } else {
+                None
+            };
+            let ctx = RenderCtx {
+                staleness_secs: Some(snapshot.staleness_secs(chrono::Utc::now())),
+                // Without the `twitter_tags`, which are for Twitter only.
+                hashtags: config.hashtags(reading.intensity.index),
+                ..notification_ctx(&config, reading, average)
+            };
+            texts.push(post_text(reading, &alert, ctx, BLUESKY_MAX_GRAPHEMES));
+        }
+        texts.extend(comparison.check(&snapshot).iter().map(Greener::to_string));
+        for text in texts {
+            let text = truncate_graphemes(&text, BLUESKY_MAX_GRAPHEMES);
+            match retry(&config.notifier_retry, || bluesky.post(&text), |_| true).await {
+                Ok(uri) => {
+                    log::info!("Posted to Bluesky: {}", uri);
+                    shared
+                        .counters
+                        .bluesky_posts
+                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+                Err(e) if config.fail_fast => return Err(Box::new(e)),
+                Err(e) => {
+                    dead_letter(
+                        &shared.counters,
+                        config.dead_letter_file.as_deref(),
+                        "bluesky",
+                        &text,
+                        &e.to_string(),
+                    )
+                    .await;
+                    continue;
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(config.tweet_interval_secs)).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Slots in the daily bar, which is 24 hours' worth. Twitter counts each
+/// emoji as two characters, so this comes to 96 of the 280.
+const DAILY_BAR_SLOTS: usize = 48;
+
+/// `Config::daily_bar_hour`: tweets the forecast for the next 24 hours once a
+/// day, until the channel closes.
+async fn run_daily_bar(
+    config: Config,
+    mut intensity_rx: tokio::sync::watch::Receiver<Option<Snapshot>>,
+    shared: std::sync::Arc<Shared>,
+) -> Result<(), Box<dyn std::error::Error + 'static + Send>> {
+    let Some(hour) = config.daily_bar_hour else {
+        return Ok(());
+    };
+    let client = config
+        .http_client()
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+    loop {
+        let now = chrono::Utc::now();
+        let at = next_daily(now, hour);
+        log::debug!("Next daily bar at {}.", at);
+        let wait = (at - now).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            // The readings themselves aren't needed, this only watches for
+            // the channel closing on shutdown.
+            _ = async { while intensity_rx.changed().await.is_ok() {} } => return Ok(()),
+        }
+        let region = config.primary_region().map_err(anyhow::Error::msg)?;
+        let window = match fetch_forecast_window(
+            &client,
+            &shared.api_rate_limit,
+            &config.api_base_url,
+            region,
+            chrono::Utc::now(),
+        )
+        .await
+        {
+            Ok(window) if !window.is_empty() => window,
+            Ok(_) => {
+                log::warn!("No forecast for the daily bar.");
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Failed to fetch the forecast for the daily bar: {}", e);
+                continue;
+            }
+        };
+        let text = daily_bar_text(&window);
+        match retry(
+            &config.notifier_retry,
+            || tweet(&config, &shared.counters, &text, None),
+            |e| !matches!(e, egg_mode::error::Error::RateLimit(_)),
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(e) if config.fail_fast => return Err(Box::new(e)),
+            Err(e) => {
+                dead_letter(
+                    &shared.counters,
+                    config.dead_letter_file.as_deref(),
+                    "twitter",
+                    &text,
+                    &e.to_string(),
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// The next time it's `hour` o'clock (UTC) after `now`.
+fn next_daily(now: chrono::DateTime<chrono::Utc>, hour: u32) -> chrono::DateTime<chrono::Utc> {
+    let today = now.date().and_hms(hour, 0, 0);
+    if today > now {
+        today
+    } else {
+        today + chrono::Duration::days(1)
+    }
+}
+
+/// The tweet for the daily bar: a square per slot, six hours to a line.
+fn daily_bar_text(window: &[Reading]) -> String {
+    let mut text = format!(
+        "Carbon intensity for {} over the next 24 hours, from {} UTC:",
+        window[0].shortname,
+        window[0].from.format("%H:%M")
+    );
+    for line in window[..window.len().min(DAILY_BAR_SLOTS)].chunks(12) {
+        text.push('\n');
+        text.extend(line.iter().map(|reading| reading.intensity.index.emoji()));
+    }
+    text
+}
+
+/// Bluesky counts a post's length in graphemes rather than characters.
+const BLUESKY_MAX_GRAPHEMES: usize = 300;
+
+/// Cuts `text` down to `max` graphemes, ending it with an ellipsis if
+/// anything had to go.
+fn truncate_graphemes(text: &str, max: usize) -> String {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    if text.graphemes(true).count() <= max {
+        return text.to_string();
+    }
+    let mut truncated: String = text.graphemes(true).take(max.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Posts to a Bluesky account over the AT Protocol's XRPC endpoints. The
+/// session is created on first use and refreshed once its access token
+/// expires.
+struct Bluesky {
+    client: reqwest::Client,
+    pds_url: String,
+    handle: String,
+    app_password: String,
+    session: tokio::sync::Mutex<Option<BlueskySession>>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlueskySession {
+    access_jwt: String,
+    refresh_jwt: String,
+    did: String,
+    handle: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CreatedRecord {
+    uri: String,
+}
+
+/// Body of an XRPC error response.
+#[derive(Debug, Default, serde::Deserialize)]
+struct XrpcErrorResponse {
+    #[serde(default)]
+    error: String,
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Debug)]
+enum XrpcError {
+    /// The server answered with an error.
+    Response {
+        status: reqwest::StatusCode,
+        body: XrpcErrorResponse,
+    },
+    /// There was no answer, or it couldn't be read.
+    Transport(reqwest::Error),
+}
+
+impl XrpcError {
+    fn is_expired_token(&self) -> bool {
+        matches!(self, XrpcError::Response { body, .. } if body.error == "ExpiredToken")
+    }
+}
+
+impl From<reqwest::Error> for XrpcError {
+    fn from(e: reqwest::Error) -> Self {
+        XrpcError::Transport(e)
+    }
+}
+
+impl std::fmt::Display for XrpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            XrpcError::Response { status, body } => {
+                write!(f, "{} {}: {}", status, body.error, body.message)
+            }
+            XrpcError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for XrpcError {}
+
+impl Bluesky {
+    /// The client for `config`'s Bluesky account, if it has one.
+    fn new(config: &Config, client: reqwest::Client) -> Option<Self> {
+        Some(Bluesky {
+            client,
+            pds_url: config.bluesky_pds_url.trim_end_matches('/').to_string(),
+            handle: config.bluesky_handle.clone()?,
+            app_password: config.bluesky_app_password.clone(),
+            session: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Posts `text`, returning the new post's URI.
+    async fn post(&self, text: &str) -> Result<String, XrpcError> {
+        let mut session = self.session.lock().await;
+        let current = match session.take() {
+            Some(current) => current,
+            None => self.create_session().await?,
+        };
+        let record = serde_json::json!({
+            "$type": "app.bsky.feed.post",
+            "text": text,
+            "createdAt": chrono::Utc::now().to_rfc3339(),
+        });
+        let (current, res) = match self.create_record(&current, &record).await {
+            Err(e) if e.is_expired_token() => {
+                log::debug!("Bluesky access token expired, refreshing the session.");
+                let refreshed = match self.refresh_session(&current).await {
+                    Ok(refreshed) => refreshed,
+                    // The refresh token expires too, eventually.
+                    Err(e) => {
+                        log::debug!("Failed to refresh the Bluesky session: {}", e);
+                        self.create_session().await?
+                    }
+                };
+                let res = self.create_record(&refreshed, &record).await;
+                (refreshed, res)
+            }
+            res => (current, res),
+        };
+        *session = Some(current);
+        res.map(|created| created.uri)
+    }
+
+    async fn create_session(&self) -> Result<BlueskySession, XrpcError> {
+        let body = serde_json::json!({
+            "identifier": self.handle,
+            "password": self.app_password,
+        });
+        self.xrpc("com.atproto.server.createSession", None, Some(&body))
+            .await
+    }
+
+    async fn refresh_session(&self, session: &BlueskySession) -> Result<BlueskySession, XrpcError> {
+        self.xrpc(
+            "com.atproto.server.refreshSession",
+            Some(&session.refresh_jwt),
+            None,
+        )
+        .await
+    }
+
+    async fn create_record(
+        &self,
+        session: &BlueskySession,
+        record: &serde_json::Value,
+    ) -> Result<CreatedRecord, XrpcError> {
+        let body = serde_json::json!({
+            "repo": session.did,
+            "collection": "app.bsky.feed.post",
+            "record": record,
+        });
+        self.xrpc(
+            "com.atproto.repo.createRecord",
+            Some(&session.access_jwt),
+            Some(&body),
+        )
+        .await
+    }
+
+    /// Calls the procedure `method`, authenticated with `token` if given.
+    async fn xrpc<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        token: Option<&str>,
+        body: Option<&serde_json::Value>,
+    ) -> Result<T, XrpcError> {
+        let mut request = self
+            .client
+            .post(format!("{}/xrpc/{}", self.pds_url, method));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let resp = request.send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.json().await.unwrap_or_default();
+            return Err(XrpcError::Response { status, body });
+        }
+        Ok(resp.json().await?)
+    }
+}
+
+/// Runs `op` until it succeeds, fails with an error that isn't `retryable`,
+/// or has been tried as often as `policy` allows.
+async fn retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    mut op: F,
+    retryable: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut backoff = Duration::from_secs(policy.backoff_secs);
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < policy.attempts && retryable(&e) => {
+                log::warn!(
+                    "Attempt {} of {} failed, retrying in {:?}: {}",
+                    attempt,
+                    policy.attempts,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// A token bucket, which lets everything through until it's given a limit.
+/// Every request to the API waits its turn at `Shared::api_rate_limit`, see
+/// `Config::api_requests_per_minute`.
+#[derive(Debug, Default)]
+struct RateLimiter {
+    bucket: std::sync::Mutex<Option<TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: tokio::time::Instant,
+}
+
+impl RateLimiter {
+    /// Allows `per_minute` requests a minute from now on, in bursts of up to
+    /// ten seconds' worth.
+    fn set_limit(&self, per_minute: u32) {
+        let per_sec = f64::from(per_minute) / 60.0;
+        let capacity = (per_sec * 10.0).max(1.0);
+        *self.bucket.lock().unwrap() = Some(TokenBucket {
+            per_sec,
+            capacity,
+            tokens: capacity,
+            updated: tokio::time::Instant::now(),
+        });
+    }
+
+    /// Waits until a request may go out.
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let Some(bucket) = bucket.as_mut() else {
+                    return;
+                };
+                let now = tokio::time::Instant::now();
+                let elapsed = (now - bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * bucket.per_sec).min(bucket.capacity);
+                bucket.updated = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.per_sec)
+            };
+            log::debug!("API rate limit reached, waiting {:?}.", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Records a notification that couldn't be delivered, appending it to `path`
+/// if there is one. Failing to do so is only logged, as there's nowhere left
+/// to report it to.
+async fn dead_letter(
+    counters: &Counters,
+    path: Option<&std::path::Path>,
+    notifier: &str,
+    payload: &str,
+    error: &str,
+) {
+    use tokio::io::AsyncWriteExt;
+
+    let total = counters
+        .dead_letters
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        + 1;
+    log::error!(
+        "Giving up on {} notification, dead_lettered_notifications_total={}: {}",
+        notifier,
+        total,
+        error
+    );
+    let Some(path) = path else {
+        return;
+    };
+    let mut line = serde_json::json!({
+        "at": chrono::Utc::now().to_rfc3339(),
+        "notifier": notifier,
+        "payload": payload,
+        "error": error,
+    })
+    .to_string();
+    line.push('\n');
+    let res = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        // Flush before the file is dropped.
+        file.flush().await
+    };
+    if let Err(e) = res.await {
+        log::error!(
+            "Failed to write to dead letter file {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Appends polled readings to a file as JSON lines.
+struct ReadingsLog {
+    path: PathBuf,
+    rotate_daily: bool,
+    /// Whether the last write failed. Failures are only logged when this
+    /// changes, so an unwritable file doesn't flood the log every poll.
+    failing: bool,
+}
+
+impl ReadingsLog {
+    fn new(path: PathBuf, rotate_daily: bool) -> Self {
+        ReadingsLog {
+            path,
+            rotate_daily,
+            failing: false,
+        }
+    }
+
+    /// The file to write to on `date`: `path` itself, or with rotation,
+    /// `path` with the date inserted before its extension.
+    fn path_on(&self, date: chrono::NaiveDate) -> PathBuf {
+        if !self.rotate_daily {
+            return self.path.clone();
+        }
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, date, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, date),
+        };
+        self.path.with_file_name(name)
+    }
+
+    async fn append(&mut self, snapshot: &Snapshot, now: chrono::DateTime<chrono::Utc>) {
+        use tokio::io::AsyncWriteExt;
+
+        let path = self.path_on(now.date().naive_utc());
+        let mut lines = String::new();
+        for reading in std::iter::once(&snapshot.primary).chain(&snapshot.others) {
+            match serde_json::to_string(reading) {
+                Ok(line) => {
+                    lines.push_str(&line);
+                    lines.push('\n');
+                }
+                Err(e) => log::error!("Failed to serialize reading {:?}: {}", reading, e),
+            }
+        }
+        let res = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            file.write_all(lines.as_bytes()).await?;
+            // Hands the data to the OS straight away rather than whenever
+            // tokio gets round to it, so a crash doesn't lose it.
+            file.flush().await
+        };
+        match res.await {
+            Ok(()) if self.failing => {
+                log::info!("Writing to readings log {} again.", path.display());
+                self.failing = false;
+            }
+            Ok(()) => {}
+            Err(e) if !self.failing => {
+                log::error!(
+                    "Failed to write to readings log {}, not logging further failures until it works again: {}",
+                    path.display(),
+                    e
+                );
+                self.failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Schema changes for `SqliteSink`, applied in order. The database's
+/// `user_version` is how many of them it has had, so only ever add to the end.
+const SQLITE_MIGRATIONS: &[&str] = &["CREATE TABLE readings (
+        timestamp TEXT NOT NULL,
+        region INTEGER NOT NULL,
+        intensity_index TEXT NOT NULL,
+        forecast INTEGER NOT NULL,
+        renewables_pct REAL
+    );
+    CREATE INDEX readings_region_timestamp ON readings (region, timestamp);"];
+
+/// `Config::sqlite_path`.
+struct SqliteSink {
+    conn: std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    renewable_fuels: Vec<String>,
+    /// Whether the last insert failed, like `ReadingsLog::failing`.
+    failing: bool,
+}
+
+impl SqliteSink {
+    fn open(path: &std::path::Path, renewable_fuels: Vec<String>) -> rusqlite::Result<Self> {
+        let mut conn = rusqlite::Connection::open(path)?;
+        // A crash mid-insert can't corrupt the database in WAL mode, and
+        // others can query it while we write.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        let applied: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (version, migration) in SQLITE_MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            log::info!("Migrating {} to version {}.", path.display(), version + 1);
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", version as i64 + 1)?;
+            tx.commit()?;
+        }
+        Ok(SqliteSink {
+            conn: std::sync::Arc::new(std::sync::Mutex::new(conn)),
+            renewable_fuels,
+            failing: false,
+        })
+    }
+
+    /// Inserts a row per reading in `snapshot`, all or none of them.
+    async fn insert(&mut self, snapshot: &Snapshot, now: chrono::DateTime<chrono::Utc>) {
+        let timestamp = now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let rows: Vec<_> = std::iter::once(&snapshot.primary)
+            .chain(&snapshot.others)
+            .map(|reading| {
+                let mix = &reading.generation_mix;
+                (
+                    reading.region as u16,
+                    format!("{:?}", reading.intensity.index),
+                    reading.intensity.forecast.0,
+                    (!mix.is_empty()).then(|| fuel_share(mix, &self.renewable_fuels)),
+                )
+            })
+            .collect();
+        let conn = self.conn.clone();
+        let res = tokio::task::spawn_blocking(move || -> rusqlite::Result<()> {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            let tx = conn.transaction()?;
+            {
+                let mut insert = tx.prepare_cached(
+                    "INSERT INTO readings (timestamp, region, intensity_index, forecast, renewables_pct)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?;
+                for (region, index, forecast, renewables) in &rows {
+                    insert.execute(rusqlite::params![timestamp, region, index, forecast, renewables])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|res| res.map_err(|e| e.to_string()));
+        match res {
+            Ok(()) if self.failing => {
+                log::info!("Inserting into the SQLite database again.");
+                self.failing = false;
+            }
+            Ok(()) => {}
+            Err(e) if !self.failing => {
+                log::error!(
+                    "Failed to insert into the SQLite database, not logging further failures until it works again: {}",
+                    e
+                );
+                self.failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// How long to wait for a Twitter rate limit resetting at the Unix timestamp
+/// `reset`.
+fn rate_limit_pause(reset: i32, now: chrono::DateTime<chrono::Utc>) -> Duration {
+    Duration::from_secs((i64::from(reset) - now.timestamp()).max(0) as u64)
+}
+
+/// Why a reading is worth notifying about.
+#[derive(Debug, Clone, PartialEq)]
+enum Alert {
+    /// The index differs from the one last notified about.
+    Changed,
+    /// The forecast rose by `delta` gCO2/kWh within `window`.
+    Rising {
+        delta: GramsPerKwh,
+        window: Duration,
+    },
+    /// The index dropped to the recovery index or below, from above it.
+    Recovered,
+}
+
+/// A region from `compare_regions` that's greener than the primary one.
+#[derive(Debug, Clone, PartialEq)]
+struct Greener {
+    region: String,
+    than: String,
+    by: GramsPerKwh,
+}
+
+impl std::fmt::Display for Greener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is {} cleaner than {} right now.",
+            self.region, self.by, self.than
+        )
+    }
+}
+
+/// Watches `compare_regions` for becoming greener than the primary region.
+/// Each one is reported once it's ahead by the margin, and again only after
+/// its lead has dropped below half of that, so a lead hovering around the
+/// margin doesn't cause a flurry of notifications.
+#[derive(Debug)]
+struct RegionComparison {
+    regions: Vec<RegionId>,
+    margin: GramsPerKwh,
+    /// The regions reported on and still ahead.
+    ahead: std::collections::HashSet<RegionId>,
+}
+
+impl RegionComparison {
+    fn new(regions: Vec<RegionId>, margin: GramsPerKwh) -> Self {
+        RegionComparison {
+            regions,
+            margin,
+            ahead: std::collections::HashSet::new(),
+        }
+    }
+
+    /// The compared regions in `snapshot` that have just pulled ahead.
+    fn check(&mut self, snapshot: &Snapshot) -> Vec<Greener> {
+        let primary = &snapshot.primary;
+        let mut greener = Vec::new();
+        for reading in &snapshot.others {
+            if !self.regions.contains(&reading.region) {
+                continue;
+            }
+            let by = primary
+                .intensity
+                .forecast
+                .saturating_sub(reading.intensity.forecast);
+            if by >= self.margin {
+                if self.ahead.insert(reading.region) {
+                    greener.push(Greener {
+                        region: reading.shortname.clone(),
+                        than: primary.shortname.clone(),
+                        by,
+                    });
+                }
+            } else if by.0 * 2 < self.margin.0 {
+                self.ahead.remove(&reading.region);
+            }
+        }
+        greener
+    }
+}
+
+impl Drop for AlertState {
+    fn drop(&mut self) {
+        self.size.resize(self.recent.len(), 0);
+    }
+}
+
+/// Decides which readings are worth notifying about.
+#[derive(Debug)]
+struct AlertState {
+    /// The index last notified about. During the startup quiet period, this
+    /// tracks the latest index instead, so that whatever is current once the
+    /// period ends doesn't trigger a notification by itself.
+    baseline: Option<Intensity>,
+    quiet_until: Instant,
+    /// Threshold and window for `Alert::Rising`.
+    rate_alert: Option<(GramsPerKwh, Duration)>,
+    /// Forecasts seen within the rate alert window, oldest first.
+    recent: std::collections::VecDeque<(Instant, GramsPerKwh)>,
+    /// The most forecasts to keep in `recent`.
+    max_entries: usize,
+    /// How many consecutive readings a new index needs before it replaces
+    /// the baseline.
+    debounce: usize,
+    /// A new index that hasn't held for long enough yet, and for how many
+    /// readings it has.
+    pending: Option<(Intensity, usize)>,
+    /// How long to hold back notifications about the index last notified
+    /// about.
+    cooldown: Option<Duration>,
+    /// The index of the last notification, and when it went out.
+    last_alert: Option<(Intensity, Instant)>,
+    /// Readings below this index aren't notified about, though they still
+    /// move the baseline.
+    threshold: Option<Intensity>,
+    /// How many readings to see before rate alerts go out.
+    warmup: usize,
+    /// Readings seen so far, counted up to `warmup`.
+    seen: usize,
+    /// Changes from above this index to it or below are `Alert::Recovered`.
+    recovery: Option<Intensity>,
+    size: BufferSize,
+}
+
+impl AlertState {
+    /// The alert state for a notifier started at `started`, as configured
+    /// globally and in its `overrides`.
+    fn from_config(config: &Config, overrides: &NotifierOverrides, started: Instant) -> Self {
+        let mut alerts = AlertState::new(started, Duration::from_secs(config.startup_quiet_secs));
+        if let Some(delta) = config.rate_alert_delta {
+            alerts =
+                alerts.with_rate_alert(delta, Duration::from_secs(config.rate_alert_window_secs));
+        }
+        alerts = alerts
+            .with_debounce(config.debounce_readings)
+            .with_warmup(config.warmup_samples)
+            .with_max_entries(config.buffer_limit(config.buffer_limits.rate_alert));
+        let cooldown = overrides
+            .same_condition_cooldown_secs
+            .or(config.same_condition_cooldown_secs);
+        if let Some(secs) = cooldown {
+            alerts = alerts.with_cooldown(Duration::from_secs(secs));
+        }
+        if let Some(threshold) = overrides.notify_threshold.or(config.notify_threshold) {
+            alerts = alerts.with_threshold(threshold);
+        }
+        if config.recovery_notify {
+            alerts = alerts.with_recovery(config.recovery_index);
+        }
+        alerts
+    }
+
+    fn new(started: Instant, quiet: Duration) -> Self {
+        AlertState {
+            baseline: None,
+            quiet_until: started + quiet,
+            rate_alert: None,
+            recent: std::collections::VecDeque::new(),
+            max_entries: default_max_buffer_entries(),
+            debounce: 1,
+            pending: None,
+            cooldown: None,
+            last_alert: None,
+            threshold: None,
+            warmup: 0,
+            seen: 0,
+            recovery: None,
+            size: BufferSize::default(),
+        }
+    }
+
+    fn with_recovery(mut self, index: Intensity) -> Self {
+        self.recovery = Some(index);
+        self
+    }
+
+    fn with_warmup(mut self, samples: usize) -> Self {
+        self.warmup = samples;
+        self
+    }
+
+    fn with_max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max.max(1);
+        self
+    }
+
+    fn with_threshold(mut self, threshold: Intensity) -> Self {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = Some(cooldown);
+        self
+    }
+
+    fn with_debounce(mut self, readings: usize) -> Self {
+        self.debounce = readings.max(1);
+        self
+    }
+
+    fn with_rate_alert(mut self, delta: GramsPerKwh, window: Duration) -> Self {
+        self.rate_alert = Some((delta, window));
+        self
+    }
+
+    /// Accounts for the forecasts kept for rate alerts in `size`.
+    fn with_buffer_size(mut self, size: BufferSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Returns whether and why `intensity` should be notified about, updating
+    /// the baseline accordingly.
+    fn decide(&mut self, intensity: &IntensityResponse, now: Instant) -> Option<Alert> {
+        let alert = self.evaluate(intensity, now)?;
+        // Recoveries are good news, which the threshold is there to leave out.
+        if alert != Alert::Recovered
+            && matches!(self.threshold, Some(threshold) if intensity.index < threshold)
+        {
+            log::debug!(
+                "{:?} is below the threshold, not notifying about {:?}.",
+                intensity.index,
+                alert
+            );
+            return None;
+        }
+        if let (Some(cooldown), Some((index, at))) = (self.cooldown, self.last_alert) {
+            if index == intensity.index && now.duration_since(at) < cooldown {
+                log::debug!(
+                    "Already notified about {:?} within {:?}, holding back {:?}.",
+                    index,
+                    cooldown,
+                    alert
+                );
+                return None;
+            }
+        }
+        self.last_alert = Some((intensity.index, now));
+        Some(alert)
+    }
+
+    /// Like `decide`, but without the cooldown.
+    fn evaluate(&mut self, intensity: &IntensityResponse, now: Instant) -> Option<Alert> {
+        let before = self.recent.len();
+        let mut rising = self.track_rate(intensity.forecast, now);
+        self.size.resize(before, self.recent.len());
+        if self.seen < self.warmup {
+            self.seen += 1;
+            if let Some(alert) = rising.take() {
+                log::debug!(
+                    "Warming up, {} of {} readings seen, not notifying about {:?}.",
+                    self.seen,
+                    self.warmup,
+                    alert
+                );
+            }
+        }
+        if now < self.quiet_until {
+            log::debug!("Startup quiet period, not notifying about {:?}.", intensity);
+            self.baseline = Some(intensity.index);
+            return None;
+        }
+        if self.baseline == Some(intensity.index) {
+            self.pending = None;
+            return rising;
+        }
+        let held = match self.pending {
+            Some((index, n)) if index == intensity.index => n + 1,
+            _ => 1,
+        };
+        if held < self.debounce {
+            log::debug!(
+                "{:?} has held for {} of {} readings, not notifying yet.",
+                intensity.index,
+                held,
+                self.debounce
+            );
+            self.pending = Some((intensity.index, held));
+            return rising;
+        }
+        self.pending = None;
+        let previous = self.baseline.replace(intensity.index);
+        match (self.recovery, previous) {
+            (Some(recovery), Some(previous))
+                if intensity.index <= recovery && previous > recovery =>
+            {
+                Some(Alert::Recovered)
+            }
+            _ => Some(Alert::Changed),
+        }
+    }
+
+    /// Records `forecast` and checks it against the oldest one still inside
+    /// the window. Once that fires, the history is reset so that it takes a
+    /// further rise to fire again.
+    fn track_rate(&mut self, forecast: GramsPerKwh, now: Instant) -> Option<Alert> {
+        let (threshold, window) = self.rate_alert?;
+        while matches!(self.recent.front(), Some(&(at, _)) if now.duration_since(at) > window) {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= self.max_entries {
+            self.recent.pop_front();
+        }
+        let oldest = self.recent.front().map(|&(_, f)| f);
+        self.recent.push_back((now, forecast));
+        let delta = forecast.saturating_sub(oldest?);
+        if delta < threshold {
+            return None;
+        }
+        self.recent.clear();
+        self.recent.push_back((now, forecast));
+        Some(Alert::Rising { delta, window })
+    }
+}
+
+const CHANGED_TEMPLATE: &str =
+    "The current carbon intensity for {region} is {index} with approximately {forecast} gCO2/KWh.";
+const RECOVERED_TEMPLATE: &str = "All clear: carbon intensity for {region} is down to {index} with approximately {forecast} gCO2/KWh. A great time to use electricity.";
+const RISING_TEMPLATE: &str = "Carbon intensity for {region} is rising quickly, up {delta} gCO2/KWh in the last {window_mins} minutes to approximately {forecast} gCO2/KWh.";
+/// Appended to the other templates when the weekly average is known.
+const WEEKLY_AVERAGE_SUFFIX: &str = " That's {vs_weekly_average} the national weekly average.";
+const SCORE_SUFFIX: &str = " Greenness score: {score}/100.";
+const DESCRIPTION_SUFFIX: &str = " {description}.";
+/// Appended to templates that don't place the hashtags themselves.
+const HASHTAGS_SUFFIX: &str = " {hashtags}";
+/// How long tweets may get. Twitter counts some characters double, emoji
+/// included, so this is only a rough guide.
+const TWEET_MAX_GRAPHEMES: usize = 280;
+
+/// Values for the placeholders that only make sense for some alerts.
+#[derive(Debug, Default)]
+struct RenderCtx {
+    delta: Option<GramsPerKwh>,
+    window: Option<Duration>,
+    weekly_average: Option<GramsPerKwh>,
+    score_max: Option<GramsPerKwh>,
+    description: Option<String>,
+    /// Replaces `CHANGED_TEMPLATE`, see `SeverityOverride::template`.
+    changed_template: Option<String>,
+    /// Replaces `RECOVERED_TEMPLATE`, see `Config::recovery_template`.
+    recovery_template: Option<String>,
+    /// As they appear, e.g. `#LowCarbon`, or `@grid` in tweets. Dropped from
+    /// the end where the text would otherwise be too long.
+    hashtags: Vec<String>,
+    /// See `Snapshot::staleness_secs`.
+    staleness_secs: Option<i64>,
+}
+
+/// The placeholders `render` fills in.
+const PLACEHOLDERS: &[&str] = &[
+    "region",
+    "index",
+    "forecast",
+    "forecast_range",
+    "delta",
+    "window_mins",
+    "vs_weekly_average",
+    "score",
+    "description",
+    "staleness",
+    "hashtags",
+];
+
+/// The first thing in braces in `template` that isn't one of
+/// `PLACEHOLDERS`, for `Config::validate`.
+fn unknown_placeholder(template: &str) -> Option<&str> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start..];
+        let end = rest.find('}')?;
+        if !PLACEHOLDERS.contains(&&rest[1..end]) {
+            return Some(&rest[..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    None
+}
+
+/// Fills in the placeholders in `template`: `{region}`, `{index}`,
+/// `{forecast}`, `{forecast_range}` if the reading has one, and if `ctx` has
+/// them, `{delta}`, `{window_mins}`,
+/// `{vs_weekly_average}`, `{score}`, `{description}` and `{staleness}`, in
+/// seconds. `{hashtags}` is empty if there aren't any.
+/// Anything else in braces, including placeholders without a value, is left
+/// as it is. Configured templates can't have anything else, see
+/// `unknown_placeholder`.
+fn render(template: &str, reading: &IntensityResponse, region: &str, ctx: &RenderCtx) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let placeholder = &rest[..=end];
+        let value = match &placeholder[1..placeholder.len() - 1] {
+            "region" => Some(region.to_string()),
+            "index" => Some(format!("{:?}", reading.index)),
+            "forecast" => Some(reading.forecast.0.to_string()),
+            "forecast_range" => reading
+                .forecast_range()
+                .map(|(low, high)| format!("{}-{}", low.0, high.0)),
+            "delta" => ctx.delta.map(|d| d.0.to_string()),
+            "window_mins" => ctx.window.map(|w| (w.as_secs() / 60).to_string()),
+            "vs_weekly_average" => ctx
+                .weekly_average
+                .and_then(|average| compare_to_average(reading.forecast, average)),
+            "score" => ctx
+                .score_max
+                .map(|max| greenness_score(reading.forecast, max).to_string()),
+            "description" => ctx.description.clone(),
+            "staleness" => ctx.staleness_secs.map(|secs| secs.to_string()),
+            "hashtags" => Some(ctx.hashtags.join(" ")),
+            _ => None,
+        };
+        match value {
+            Some(value) => out.push_str(&value),
+            None => {
+                log::warn!("No value for {} in template.", placeholder);
+                out.push_str(placeholder);
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether `tag`, without its `#`, works as a hashtag.
+fn valid_hashtag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !tag.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Scores `forecast` from 100 at 0 gCO2/kWh down to 0 at `max`, linearly:
+/// `round(100 * (1 - forecast / max))`, clamped to 0..=100.
+fn greenness_score(forecast: GramsPerKwh, max: GramsPerKwh) -> u8 {
+    let score = (1.0 - f64::from(forecast) / f64::from(max)) * 100.0;
+    score.round().clamp(0.0, 100.0) as u8
+}
+
+/// Describes `forecast` relative to `average`, e.g. "30% above".
+fn compare_to_average(forecast: GramsPerKwh, average: GramsPerKwh) -> Option<String> {
+    if average == GramsPerKwh(0) {
+        return None;
+    }
+    let percent = ((f64::from(forecast) - f64::from(average)) / f64::from(average) * 100.0).round();
+    Some(if percent > 0.0 {
+        format!("{}% above", percent)
+    } else if percent < 0.0 {
+        format!("{}% below", -percent)
+    } else {
+        "in line with".to_string()
+    })
+}
+
+/// The placeholder values for notifications that come from the config
+/// rather than the alert.
+fn notification_ctx(
+    config: &Config,
+    reading: &Reading,
+    weekly_average: Option<GramsPerKwh>,
+) -> RenderCtx {
+    RenderCtx {
+        weekly_average,
+        score_max: config.score_max,
+        description: config
+            .describe_index
+            .then(|| config.description(reading.intensity.index).to_string()),
+        changed_template: config
+            .severity
+            .get(&reading.intensity.index)
+            .and_then(|severity| severity.template.clone()),
+        hashtags: config.hashtags(reading.intensity.index),
+        recovery_template: config.recovery_template.clone(),
+        ..RenderCtx::default()
+    }
+}
+
+/// Renders the template for `alert`, followed by a suffix for each of the
+/// extras in `ctx` that's set. Hashtags that would take it over
+/// `TWEET_MAX_GRAPHEMES` are left out.
+fn tweet_text(reading: &Reading, alert: &Alert, ctx: RenderCtx) -> String {
+    post_text(reading, alert, ctx, TWEET_MAX_GRAPHEMES)
+}
+
+/// `tweet_text` for a service that allows `max_graphemes`.
+fn post_text(reading: &Reading, alert: &Alert, ctx: RenderCtx, max_graphemes: usize) -> String {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    let (mut template, ctx) = match alert {
+        Alert::Changed => (
+            ctx.changed_template
+                .clone()
+                .unwrap_or_else(|| CHANGED_TEMPLATE.to_string()),
+            ctx,
+        ),
+        Alert::Recovered => (
+            ctx.recovery_template
+                .clone()
+                .unwrap_or_else(|| RECOVERED_TEMPLATE.to_string()),
+            ctx,
+        ),
+        Alert::Rising { delta, window } => (
+            RISING_TEMPLATE.to_string(),
+            RenderCtx {
+                delta: Some(*delta),
+                window: Some(*window),
+                ..ctx
+            },
+        ),
+    };
+    if ctx.description.is_some() {
+        template.push_str(DESCRIPTION_SUFFIX);
+    }
+    // There's nothing to compare to an average of 0.
+    if ctx
+        .weekly_average
+        .and_then(|average| compare_to_average(reading.intensity.forecast, average))
+        .is_some()
+    {
+        template.push_str(WEEKLY_AVERAGE_SUFFIX);
+    }
+    if ctx.score_max.is_some() {
+        template.push_str(SCORE_SUFFIX);
+    }
+    if !ctx.hashtags.is_empty() && !template.contains("{hashtags}") {
+        template.push_str(HASHTAGS_SUFFIX);
+    }
+    let mut ctx = ctx;
+    loop {
+        let text = render(&template, &reading.intensity, &reading.shortname, &ctx);
+        let text = text.trim_end();
+        if ctx.hashtags.is_empty() || text.graphemes(true).count() <= max_graphemes {
+            return text.to_string();
+        }
+        ctx.hashtags.pop();
+    }
+}
+
+fn twitter_token(config: &Config) -> egg_mode::Token {
+    let con_token = egg_mode::KeyPair::new(
+        config.twitter_consumer_key.to_string(),
+        config.twitter_consumer_secret.to_string(),
+    );
+    let access_token = egg_mode::KeyPair::new(
+        config.twitter_access_token.to_string(),
+        config.twitter_access_secret.to_string(),
+    );
+    egg_mode::Token::Access {
+        consumer: con_token,
+        access: access_token,
+    }
+}
+
+/// Whether Twitter refused a reply because the tweet replied to is gone.
+fn replied_to_deleted(e: &egg_mode::error::Error) -> bool {
+    // "You attempted to reply to a Tweet that is deleted or not visible to
+    // you."
+    const REPLY_TO_DELETED: i32 = 385;
+
+    matches!(e, egg_mode::error::Error::TwitterError(_, errors)
+        if errors.errors.iter().any(|e| e.code == REPLY_TO_DELETED))
+}
+
+/// The day's thread with `Config::thread_daily`: the last tweet posted, to
+/// reply to, and the day it was posted on.
+#[derive(Debug, Default)]
+struct Thread {
+    last: Option<(chrono::NaiveDate, u64)>,
+}
+
+impl Thread {
+    /// The tweet to reply to on `today`, if there's been one.
+    fn parent(&self, today: chrono::NaiveDate) -> Option<u64> {
+        self.last
+            .filter(|&(date, _)| date == today)
+            .map(|(_, id)| id)
+    }
+
+    fn posted(&mut self, today: chrono::NaiveDate, id: u64) {
+        self.last = Some((today, id));
+    }
+}
+
+/// Posts `text`, as a reply to the tweet with the ID `in_reply_to` if set.
+async fn tweet(
+    config: &Config,
+    counters: &Counters,
+    text: &str,
+    in_reply_to: Option<u64>,
+) -> Result<egg_mode::Response<egg_mode::tweet::Tweet>, egg_mode::error::Error> {
+    use egg_mode::tweet::DraftTweet;
+
+    let mut draft = DraftTweet::new(text.to_string());
+    if let Some(id) = in_reply_to {
+        draft = draft.in_reply_to(id);
+    }
+    let post = draft.send(&twitter_token(config)).await?;
+    counters
+        .tweets
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    dbg!(&post);
+
+    Ok(post)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A response for London, as returned by the regional endpoints.
+    const LONDON_FIXTURE: &str = include_str!("../fixtures/london.json");
+
+    #[test]
+    fn test_timestamp() {
+        let j = LONDON_FIXTURE;
+        let jd = &mut serde_json::Deserializer::from_str(j);
+        let res: RegionalResponse = serde_path_to_error::deserialize(jd).unwrap();
+        insta::assert_debug_snapshot!(res);
+    }
+
+    #[test]
+    fn test_error() {
+        let j = r#"
+{
+    "error": {
         "code": "400 Bad Request",
         "message": "Please enter a valid region ID i.e. 1-17."
     }
 }
         "#;
-        let jd = &mut serde_json::Deserializer::from_str(j);
-        let res: RegionalResponse = serde_path_to_error::deserialize(jd).unwrap();
-        insta::assert_debug_snapshot!(res);
+        let jd = &mut serde_json::Deserializer::from_str(j);
+        let res: RegionalResponse = serde_path_to_error::deserialize(jd).unwrap();
+        insta::assert_debug_snapshot!(res);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_bounded() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let in_flight = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let regions = [
+            RegionId::NorthScotland,
+            RegionId::SouthScotland,
+            RegionId::NorthWestEngland,
+            RegionId::NorthEastEngland,
+            RegionId::SouthYorkshire,
+            RegionId::NorthWales,
+            RegionId::SouthWales,
+            RegionId::London,
+        ];
+        let res = fetch_bounded(&regions, 3, |region| {
+            let (in_flight, peak) = (&in_flight, &peak);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, ()>(region)
+            }
+        })
+        .await;
+
+        assert_eq!(res, regions.iter().map(|&r| Ok(r)).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_select_slot() {
+        use chrono::TimeZone;
+        let t = |h, m| chrono::Utc.ymd(2021, 12, 13).and_hms(h, m, 0);
+        let spans = [
+            (t(16, 0), t(16, 30)),
+            (t(16, 30), t(17, 0)),
+            (t(17, 0), t(17, 30)),
+        ];
+        let select = |selection, now| select_slot(&spans, selection, now);
+
+        assert_eq!(select(SlotSelection::First, t(16, 45)), Some(0));
+        assert_eq!(select(SlotSelection::Current, t(16, 45)), Some(1));
+        assert_eq!(select(SlotSelection::Next, t(16, 45)), Some(2));
+        // Past the end, the last slot is the best we have.
+        assert_eq!(select(SlotSelection::Current, t(18, 0)), Some(2));
+        assert_eq!(select(SlotSelection::Next, t(17, 15)), Some(2));
+        // Before the start, the earliest slot is both current and next.
+        assert_eq!(select(SlotSelection::Current, t(15, 0)), Some(0));
+        assert_eq!(select(SlotSelection::Next, t(15, 0)), Some(0));
+        assert_eq!(select_slot(&[], SlotSelection::Current, t(16, 45)), None);
+    }
+
+    #[test]
+    fn test_slot_offset() {
+        use chrono::TimeZone;
+        let t = |h, m| chrono::Utc.ymd(2021, 12, 13).and_hms(h, m, 0);
+        let spans = [
+            (t(16, 0), t(16, 30)),
+            (t(16, 30), t(17, 0)),
+            (t(17, 0), t(17, 30)),
+        ];
+        let pick = |selection, offset| {
+            let skew = chrono::Duration::zero();
+            pick_slot(
+                &spans,
+                SlotSpec {
+                    selection,
+                    offset,
+                    skew,
+                },
+                t(16, 15),
+            )
+        };
+
+        assert_eq!(pick(SlotSelection::First, 0), Ok(0));
+        assert_eq!(pick(SlotSelection::First, 2), Ok(2));
+        assert_eq!(pick(SlotSelection::Next, 1), Ok(2));
+        assert_eq!(
+            pick(SlotSelection::First, 3),
+            Err("Slot offset 3 is past the 3 slots in the response".to_string())
+        );
+        assert_eq!(
+            pick_slot(
+                &[],
+                SlotSpec {
+                    selection: SlotSelection::First,
+                    offset: 0,
+                    skew: chrono::Duration::zero(),
+                },
+                t(16, 15)
+            ),
+            Err("No forecast slots in response".to_string())
+        );
+    }
+
+    #[test]
+    fn test_boundary_skew() {
+        use chrono::TimeZone;
+        let t = |h, m, s| chrono::Utc.ymd(2021, 12, 13).and_hms(h, m, s);
+        let rolled = [(t(16, 0, 0), t(16, 30, 0)), (t(16, 30, 0), t(17, 0, 0))];
+        let lagging = [(t(15, 30, 0), t(16, 0, 0)), (t(16, 0, 0), t(16, 30, 0))];
+        let spec = |secs| SlotSpec {
+            selection: SlotSelection::Current,
+            offset: 0,
+            skew: chrono::Duration::seconds(secs),
+        };
+        let just_after = t(16, 30, 5);
+
+        // Without a skew, the slot reported a few seconds after :30 depends on
+        // whether the API has rolled over yet.
+        assert_eq!(pick_slot(&rolled, spec(0), just_after), Ok(1));
+        assert_eq!(pick_slot(&lagging, spec(0), just_after), Ok(1));
+        assert_ne!(rolled[1], lagging[1]);
+
+        // With one, it's the slot that just ended either way.
+        assert_eq!(pick_slot(&rolled, spec(30), just_after), Ok(0));
+        assert_eq!(pick_slot(&lagging, spec(30), just_after), Ok(1));
+        assert_eq!(rolled[0], lagging[1]);
+
+        // Once the skew has passed, the new slot takes over.
+        assert_eq!(pick_slot(&rolled, spec(30), t(16, 30, 30)), Ok(1));
+        assert_eq!(pick_slot(&rolled, spec(30), t(16, 15, 0)), Ok(0));
+    }
+
+    #[test]
+    fn test_startup_quiet() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut alerts = AlertState::new(start, Duration::from_secs(60));
+        let mut notifies = |index, secs| {
+            let intensity = IntensityResponse {
+                index,
+                forecast: GramsPerKwh(0),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            };
+            alerts.decide(&intensity, at(secs)).is_some()
+        };
+
+        assert!(!notifies(Intensity::VeryHigh, 0));
+        assert!(!notifies(Intensity::High, 30));
+        // The reading current when the quiet period ends is the baseline.
+        assert!(!notifies(Intensity::High, 60));
+        assert!(notifies(Intensity::Moderate, 90));
+        assert!(!notifies(Intensity::Moderate, 120));
+
+        // Without anything seen during the quiet period, the first reading
+        // after it notifies.
+        let mut alerts = AlertState::new(start, Duration::from_secs(60));
+        let intensity = IntensityResponse {
+            index: Intensity::Low,
+            forecast: GramsPerKwh(0),
+            actual: None,
+            forecast_low: None,
+            forecast_high: None,
+        };
+        assert_eq!(alerts.decide(&intensity, at(61)), Some(Alert::Changed));
+    }
+
+    #[test]
+    fn test_rate_alert() {
+        let start = Instant::now();
+        let window = Duration::from_secs(30 * 60);
+        let mut alerts =
+            AlertState::new(start, Duration::from_secs(0)).with_rate_alert(GramsPerKwh(50), window);
+        let mut decide = |forecast, mins: u64| {
+            let intensity = IntensityResponse {
+                index: Intensity::Moderate,
+                forecast: GramsPerKwh(forecast),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            };
+            alerts.decide(&intensity, start + Duration::from_secs(mins * 60))
+        };
+
+        assert_eq!(decide(150, 0), Some(Alert::Changed));
+        assert_eq!(decide(170, 10), None);
+        assert_eq!(decide(199, 20), None);
+        assert_eq!(
+            decide(200, 30),
+            Some(Alert::Rising {
+                delta: GramsPerKwh(50),
+                window
+            })
+        );
+        // Holding steady after an alert doesn't repeat it.
+        assert_eq!(decide(200, 40), None);
+        // A rise spread over more than the window doesn't count.
+        assert_eq!(decide(225, 65), None);
+        assert_eq!(decide(249, 90), None);
+        // Falling never does.
+        assert_eq!(decide(100, 100), None);
+    }
+
+    #[test]
+    fn test_metrics_text() {
+        let headers = "# HELP carbon_intensity_grams_per_kwh The reported carbon intensity.\n\
+                       # TYPE carbon_intensity_grams_per_kwh gauge\n\
+                       # HELP carbon_generation_mix_percent Share of generation per fuel.\n\
+                       # TYPE carbon_generation_mix_percent gauge\n";
+        let counters = "# HELP carbon_alert_polls_total Successful polls since startup.\n\
+                        # TYPE carbon_alert_polls_total counter\n\
+                        carbon_alert_polls_total 3\n\
+                        # HELP carbon_alert_poll_failures_total Failed polls since startup.\n\
+                        # TYPE carbon_alert_poll_failures_total counter\n\
+                        carbon_alert_poll_failures_total 1\n\
+                        # HELP carbon_alert_tweets_total Tweets posted since startup.\n\
+                        # TYPE carbon_alert_tweets_total counter\n\
+                        carbon_alert_tweets_total 2\n\
+                        # HELP carbon_alert_bluesky_posts_total Bluesky posts since startup.\n\
+                        # TYPE carbon_alert_bluesky_posts_total counter\n\
+                        carbon_alert_bluesky_posts_total 0\n\
+                        # HELP carbon_alert_dead_letters_total Notifications given up on since startup.\n\
+                        # TYPE carbon_alert_dead_letters_total counter\n\
+                        carbon_alert_dead_letters_total 0\n\
+                        # HELP carbon_alert_failovers_total Switches to api_fallback_url since startup.\n\
+                        # TYPE carbon_alert_failovers_total counter\n\
+                        carbon_alert_failovers_total 0\n\
+                        # HELP carbon_alert_buffer_entries Entries held in memory, per buffer.\n\
+                        # TYPE carbon_alert_buffer_entries gauge\n\
+                        carbon_alert_buffer_entries{buffer=\"smoothing\"} 12\n\
+                        carbon_alert_buffer_entries{buffer=\"rate_alert\"} 0\n\
+                        carbon_alert_buffer_entries{buffer=\"relay_cache\"} 1\n";
+        let buffers = [("smoothing", 12), ("rate_alert", 0), ("relay_cache", 1)];
+        let values = CounterValues {
+            polls: 3,
+            poll_failures: 1,
+            tweets: 2,
+            bluesky_posts: 0,
+            dead_letters: 0,
+            failovers: 0,
+        };
+        assert_eq!(
+            metrics_text(None, values, buffers, polled_at()),
+            headers.to_string() + counters
+        );
+
+        let mut london = reading(RegionId::London, "London", 435);
+        london.generation_mix = vec![
+            FuelShare {
+                fuel: "wind".to_string(),
+                perc: 30.5,
+            },
+            FuelShare {
+                fuel: "gas".to_string(),
+                perc: 69.5,
+            },
+        ];
+        let snapshot = snapshot(london, vec![reading(RegionId::Scotland, "Scotland", 20)]);
+        assert_eq!(
+            metrics_text(
+                Some(&snapshot),
+                values,
+                buffers,
+                polled_at() + chrono::Duration::seconds(90)
+            ),
+            "# HELP carbon_intensity_grams_per_kwh The reported carbon intensity.\n\
+             # TYPE carbon_intensity_grams_per_kwh gauge\n\
+             carbon_intensity_grams_per_kwh{region=\"13\"} 435\n\
+             carbon_intensity_grams_per_kwh{region=\"16\"} 20\n\
+             # HELP carbon_generation_mix_percent Share of generation per fuel.\n\
+             # TYPE carbon_generation_mix_percent gauge\n\
+             carbon_generation_mix_percent{region=\"13\",fuel=\"wind\"} 30.5\n\
+             carbon_generation_mix_percent{region=\"13\",fuel=\"gas\"} 69.5\n\
+             # HELP carbon_alert_staleness_seconds Seconds since the last successful poll.\n\
+             # TYPE carbon_alert_staleness_seconds gauge\n\
+             carbon_alert_staleness_seconds 90\n"
+                .to_string()
+                + counters
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statsd() {
+        let agent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut statsd = Statsd::connect(&agent.local_addr().unwrap().to_string(), "carbon_alert")
+            .await
+            .unwrap();
+        let snapshot = snapshot(
+            reading(RegionId::London, "London", 435),
+            vec![reading(RegionId::Scotland, "Scotland", 20)],
+        );
+        let mut counters = CounterValues {
+            polls: 3,
+            poll_failures: 1,
+            tweets: 0,
+            bluesky_posts: 0,
+            dead_letters: 0,
+            failovers: 0,
+        };
+        async fn received(agent: &tokio::net::UdpSocket) -> String {
+            let mut buf = [0; 1024];
+            let len = tokio::time::timeout(Duration::from_secs(5), agent.recv(&mut buf))
+                .await
+                .expect("nothing received")
+                .unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        }
+
+        statsd.send(&snapshot, counters).await;
+        assert_eq!(
+            received(&agent).await,
+            "carbon_alert.intensity:435|g|#region:13\n\
+             carbon_alert.index:4|g|#region:13\n\
+             carbon_alert.intensity:20|g|#region:16\n\
+             carbon_alert.index:0|g|#region:16\n\
+             carbon_alert.polls:3|c\n\
+             carbon_alert.poll_failures:1|c"
+        );
+
+        // Counters are sent as increments.
+        counters.polls += 1;
+        statsd.send(&snapshot, counters).await;
+        assert!(received(&agent)
+            .await
+            .ends_with("#region:16\ncarbon_alert.polls:1|c"));
+
+        assert_eq!(
+            statsd_lines("home", &snapshot, counters, counters)[0],
+            "home.intensity:435|g|#region:13"
+        );
+
+        // Nobody listening isn't an error.
+        drop(agent);
+        statsd.send(&snapshot, counters).await;
+        statsd.send(&snapshot, counters).await;
+    }
+
+    #[test]
+    fn test_daily_bar() {
+        use chrono::TimeZone;
+
+        let morning = chrono::Utc.ymd(2021, 12, 13).and_hms(6, 30, 0);
+        assert_eq!(
+            next_daily(morning, 7),
+            chrono::Utc.ymd(2021, 12, 13).and_hms(7, 0, 0)
+        );
+        assert_eq!(
+            next_daily(chrono::Utc.ymd(2021, 12, 13).and_hms(7, 0, 0), 7),
+            chrono::Utc.ymd(2021, 12, 14).and_hms(7, 0, 0)
+        );
+
+        // More than a day's worth is cut off.
+        let window: Vec<Reading> = [20, 100, 150, 250, 400]
+            .iter()
+            .cycle()
+            .take(50)
+            .enumerate()
+            .map(|(i, &forecast)| {
+                let mut reading = reading(RegionId::London, "London", forecast);
+                reading.from = morning + chrono::Duration::minutes(30 * i as i64);
+                reading
+            })
+            .collect();
+        assert_eq!(
+            daily_bar_text(&window),
+            "Carbon intensity for London over the next 24 hours, from 06:30 UTC:\n\
+             🟩🟩🟨🟧🟥🟩🟩🟨🟧🟥🟩🟩\n\
+             🟨🟧🟥🟩🟩🟨🟧🟥🟩🟩🟨🟧\n\
+             🟥🟩🟩🟨🟧🟥🟩🟩🟨🟧🟥🟩\n\
+             🟩🟨🟧🟥🟩🟩🟨🟧🟥🟩🟩🟨"
+        );
+    }
+
+    #[test]
+    fn test_thread() {
+        let monday = chrono::NaiveDate::from_ymd(2021, 12, 13);
+        let tuesday = monday.succ();
+        let mut thread = Thread::default();
+        assert_eq!(thread.parent(monday), None);
+        thread.posted(monday, 1);
+        assert_eq!(thread.parent(monday), Some(1));
+        thread.posted(monday, 2);
+        assert_eq!(thread.parent(monday), Some(2));
+        // A new day starts a new thread.
+        assert_eq!(thread.parent(tuesday), None);
+        thread.posted(tuesday, 3);
+        assert_eq!(thread.parent(tuesday), Some(3));
+    }
+
+    #[test]
+    fn test_region_comparison() {
+        let config = try_parse_config("regions: [1], compare_regions: [2],").unwrap();
+        assert!(config.validate().is_err());
+        let config = try_parse_config("regions: [1], compare_regions: [1],").unwrap();
+        config.validate().unwrap();
+
+        let mut comparison = RegionComparison::new(vec![RegionId::NorthScotland], GramsPerKwh(50));
+        let mut check = |london, scotland, wales| {
+            comparison.check(&snapshot(
+                reading(RegionId::London, "London", london),
+                vec![
+                    reading(RegionId::NorthScotland, "North Scotland", scotland),
+                    // Not compared, however green.
+                    reading(RegionId::SouthWales, "South Wales", wales),
+                ],
+            ))
+        };
+        assert!(check(200, 160, 0).is_empty());
+        let greener = check(200, 120, 0);
+        assert_eq!(
+            greener,
+            [Greener {
+                region: "North Scotland".to_string(),
+                than: "London".to_string(),
+                by: GramsPerKwh(80),
+            }]
+        );
+        assert_eq!(
+            greener[0].to_string(),
+            "North Scotland is 80 gCO2/kWh cleaner than London right now."
+        );
+        // Staying ahead, or only dropping back a little, isn't news.
+        assert!(check(200, 100, 0).is_empty());
+        assert!(check(200, 170, 0).is_empty());
+        assert!(check(200, 140, 0).is_empty());
+        // Once it's dropped back far enough, pulling ahead again is.
+        assert!(check(200, 190, 0).is_empty());
+        assert_eq!(check(200, 150, 0).len(), 1);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let start = Instant::now();
+        let window = Duration::from_secs(30 * 60);
+        let mut alerts = AlertState::new(start, Duration::from_secs(0))
+            .with_rate_alert(GramsPerKwh(50), window)
+            .with_warmup(3);
+        let mut decide = |forecast, mins: u64| {
+            let intensity = IntensityResponse {
+                index: Intensity::Moderate,
+                forecast: GramsPerKwh(forecast),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            };
+            alerts.decide(&intensity, start + Duration::from_secs(mins * 60))
+        };
+        // Index changes still go out, but a rise within the first three
+        // readings doesn't.
+        assert_eq!(decide(150, 0), Some(Alert::Changed));
+        assert_eq!(decide(200, 10), None);
+        assert_eq!(decide(200, 20), None);
+        assert_eq!(
+            decide(250, 30),
+            Some(Alert::Rising {
+                delta: GramsPerKwh(50),
+                window
+            })
+        );
+
+        let mut smoother = Smoother::new(2).with_warmup(2);
+        let mut smooth = |forecast| {
+            let mut reading = reading(RegionId::London, "", forecast);
+            smoother.apply(&mut reading);
+            (reading.intensity.forecast.0, reading.raw_forecast)
+        };
+        assert_eq!(smooth(100), (100, None));
+        assert_eq!(smooth(200), (200, None));
+        // Warmed up, with the warmup readings counting towards the average.
+        assert_eq!(smooth(300), (250, Some(GramsPerKwh(300))));
+    }
+
+    fn parse_reading(j: &str) -> Result<Reading, Box<dyn std::error::Error>> {
+        let jd = &mut serde_json::Deserializer::from_str(j);
+        let res: RegionalResponse = serde_path_to_error::deserialize(jd)?;
+        regional_reading(
+            res,
+            RegionId::London,
+            SlotSpec {
+                selection: SlotSelection::First,
+                offset: 0,
+                skew: chrono::Duration::zero(),
+            },
+            chrono::Utc::now(),
+        )
+    }
+
+    #[test]
+    fn test_empty_data() {
+        let err = parse_reading(r#"{ "data": [] }"#).unwrap_err();
+        assert_eq!(err.to_string(), "No regions in response");
+    }
+
+    #[test]
+    fn test_empty_slots() {
+        let j = r#"
+{
+    "data": [
+        {
+            "regionid": 13,
+            "dnoregion": "UKPN London",
+            "shortname": "London",
+            "data": []
+        }
+    ]
+}
+        "#;
+        let err = parse_reading(j).unwrap_err();
+        assert_eq!(err.to_string(), "No forecast slots in response");
+    }
+
+    #[test]
+    fn test_poll_summary() {
+        assert_eq!(
+            poll_summary(&reading(RegionId::London, "London", 280)),
+            "London: high (280 gCO2/kWh) at 16:30Z"
+        );
+    }
+
+    #[test]
+    fn test_is_estimated() {
+        let j = r#"
+{
+    "data": [
+        {
+            "regionid": 13,
+            "dnoregion": "UKPN London",
+            "shortname": "London",
+            "data": [
+                {
+                    "from": "2021-12-13T16:30Z",
+                    "to": "2021-12-13T17:00Z",
+                    "intensity": {
+                        "forecast": 435,
+                        "index": "very high"
+                    }
+                }
+            ]
+        }
+    ]
+}
+        "#;
+        assert!(parse_reading(j).unwrap().is_estimated);
+
+        let measured = j.replace(r#""forecast": 435,"#, r#""forecast": 435, "actual": 420,"#);
+        assert!(!parse_reading(&measured).unwrap().is_estimated);
+    }
+
+    #[test]
+    fn test_null_index() {
+        let j = r#"
+{
+    "data": [
+        {
+            "regionid": 13,
+            "dnoregion": "UKPN London",
+            "shortname": "London",
+            "data": [
+                {
+                    "from": "2021-12-13T16:30Z",
+                    "to": "2021-12-13T17:00Z",
+                    "intensity": {
+                        "forecast": 435,
+                        "index": null
+                    }
+                }
+            ]
+        }
+    ]
+}
+        "#;
+        let reading = parse_reading(j).unwrap();
+        assert_eq!(reading.intensity.index, Intensity::VeryHigh);
+        assert_eq!(reading.intensity.forecast, GramsPerKwh(435));
+
+        let missing = j.replace(
+            r#""forecast": 435,
+                        "index": null"#,
+            r#""forecast": 120"#,
+        );
+        let reading = parse_reading(&missing).unwrap();
+        assert_eq!(reading.intensity.index, Intensity::Low);
+
+        let unknown = j.replace("null", r#""extreme""#);
+        let err = parse_reading(&unknown).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("data[0].data[0].intensity.index"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_missing_ca_cert() {
+        let mqtt: MQTTConnectionConfig = ron::de::from_str(
+            r#"(
+                host: "localhost",
+                port: 8883,
+                user: "user",
+                password: "password",
+                ca_cert_path: Some("/nonexistent/ca.pem"),
+            )"#,
+        )
+        .unwrap();
+        let err = match mqtt.tls_config() {
+            Ok(_) => panic!("missing CA file accepted"),
+            Err(e) => e,
+        };
+        assert!(
+            err.to_string()
+                .starts_with("Failed to open MQTT CA certificate /nonexistent/ca.pem"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_publish_properties() {
+        let user_properties = vec![("region".to_string(), "13".to_string())];
+        let json = Message::json(HISTORY_TOPIC, &[1, 2]).unwrap();
+        let properties = publish_properties(&json, &user_properties);
+        assert_eq!(properties.content_type.as_deref(), Some("application/json"));
+        assert_eq!(properties.user_properties, user_properties);
+
+        let forecast = Message::new(FORECAST_TOPIC, "150").with_property("unit", "gCO2/kWh");
+        assert_eq!(
+            publish_properties(&forecast, &user_properties).user_properties,
+            vec![
+                ("region".to_string(), "13".to_string()),
+                ("unit".to_string(), "gCO2/kWh".to_string()),
+            ]
+        );
+
+        let raw = Message::new(STATE_TOPIC, [1]);
+        assert_eq!(
+            publish_properties(&raw, &user_properties).content_type,
+            None
+        );
+    }
+
+    #[test]
+    fn test_discovery_config() {
+        let ha = HomeAssistantConfig {
+            discovery_prefix: "homeassistant".to_string(),
+            device_class: Some("carbon_dioxide".to_string()),
+        };
+        let (topic, config) =
+            discovery_config(&ha, FORECAST_UNIT, &Topics::default(), RegionId::London);
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/carbon_alert_13/forecast/config"
+        );
+
+        // Round-trip through the wire format to make sure it's valid JSON.
+        let config: serde_json::Value = serde_json::from_str(&config.to_string()).unwrap();
+        assert_eq!(config["state_topic"], FORECAST_TOPIC);
+        assert_eq!(config["availability_topic"], AVAILABILITY_TOPIC);
+        assert_eq!(config["unit_of_measurement"], FORECAST_UNIT);
+        assert_eq!(config["device_class"], "carbon_dioxide");
+        // The forecast is published as a bare number, so there's nothing to
+        // template.
+        assert!(config.get("value_template").is_none());
+        insta::assert_json_snapshot!(config);
+    }
+
+    /// When the snapshots in tests were polled, 10 minutes into the slot of
+    /// `reading`.
+    fn polled_at() -> chrono::DateTime<chrono::Utc> {
+        use chrono::TimeZone;
+        chrono::Utc.ymd(2021, 12, 13).and_hms(16, 40, 0)
+    }
+
+    fn reading(region: RegionId, shortname: &str, forecast: u32) -> Reading {
+        use chrono::TimeZone;
+        Reading {
+            region,
+            shortname: shortname.to_string(),
+            from: chrono::Utc.ymd(2021, 12, 13).and_hms(16, 30, 0),
+            to: chrono::Utc.ymd(2021, 12, 13).and_hms(17, 0, 0),
+            intensity: IntensityResponse {
+                index: Intensity::from_forecast(GramsPerKwh(forecast)),
+                forecast: GramsPerKwh(forecast),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            },
+            is_estimated: true,
+            raw_forecast: None,
+            generation_mix: Vec::new(),
+        }
+    }
+
+    /// A snapshot of `primary` and `others`, polled at `polled_at()`.
+    fn snapshot(primary: Reading, others: Vec<Reading>) -> Snapshot {
+        Snapshot {
+            primary,
+            others,
+            blended: None,
+            window: None,
+            notified: None,
+            polled_at: polled_at(),
+            seq: 0,
+        }
+    }
+
+    #[test]
+    fn test_blend() {
+        let readings = [
+            reading(RegionId::London, "London", 100),
+            reading(RegionId::SouthEngland, "South England", 300),
+        ];
+        let blended = blend(
+            &[(RegionId::SouthEngland, 0.25), (RegionId::London, 0.75)],
+            &readings,
+        )
+        .unwrap();
+        assert_eq!(blended.forecast, 150.0);
+        assert_eq!(blended.reading.region, RegionId::London);
+        assert_eq!(blended.reading.shortname, "South England / London");
+        assert_eq!(blended.reading.intensity.forecast, GramsPerKwh(150));
+        assert_eq!(blended.reading.intensity.index, Intensity::Moderate);
+
+        // Weights that aren't exact in binary don't pick up an f32's error.
+        let blended = blend(
+            &[(RegionId::London, 0.7), (RegionId::SouthEngland, 0.3)],
+            &readings,
+        )
+        .unwrap();
+        assert_eq!(blended.forecast, 160.0);
+
+        let err = blend(&[(RegionId::Wales, 1.0)], &readings).unwrap_err();
+        assert_eq!(err, "No reading for weighted region Wales");
+    }
+
+    fn parse_config(extra: &str) -> Config {
+        try_parse_config(extra).unwrap()
+    }
+
+    fn try_parse_config(extra: &str) -> Result<Config, ron::Error> {
+        ron::de::from_str(&format!(
+            r#"Config(
+                region: 13,
+                twitter_consumer_key: "key",
+                twitter_consumer_secret: "secret",
+                twitter_access_token: "token",
+                twitter_access_secret: "secret",
+                poll_interval_secs: 120,
+                tweet_interval_secs: 9000,
+                mqtt: (
+                    host: "localhost",
+                    port: 8883,
+                    user: "user",
+                    password: "password",
+                ),
+                {}
+            )"#,
+            extra
+        ))
+    }
+
+    #[test]
+    fn test_unknown_config_fields() {
+        let e = try_parse_config("poll_intervall_secs: 60,").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("unknown field `poll_intervall_secs`"),
+            "{}",
+            e
+        );
+
+        let e = try_parse_config("notifier_retry: (attempts: 3, backof_secs: 5),").unwrap_err();
+        assert!(
+            e.to_string().contains("unknown field `backof_secs`"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_validate_weights() {
+        assert!(parse_config("").validate().is_ok());
+        assert!(parse_config("regions_weighted: [(13, 0.7), (12, 0.3)],")
+            .validate()
+            .is_ok());
+        let err = parse_config("regions_weighted: [(13, 0.7), (12, 0.2)],")
+            .validate()
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("Region weights add up to 0.9"),
+            "{}",
+            err
+        );
+        assert!(parse_config("regions_weighted: [(13, 1.5), (12, -0.5)],")
+            .validate()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::default();
+        // Without a limit, nothing waits.
+        for _ in 0..100 {
+            limiter.acquire().await;
+        }
+
+        // A burst of ten seconds' worth goes straight through, the next
+        // request has to wait a second for its token.
+        limiter.set_limit(60);
+        let burst = async {
+            for _ in 0..10 {
+                limiter.acquire().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_millis(100), burst)
+            .await
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), limiter.acquire())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_supervise_notifier_restarts() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Panics the first time round, then exits normally.
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let name = supervise_notifier("test", 3, move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                assert!(run > 0, "first run panics");
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(name, "test");
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        // Without restarts, a failure is final.
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let e = supervise_notifier("test", 0, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { Err(anyhow::Error::msg("failed").into()) }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(e.to_string(), "test task failed: failed");
+    }
+
+    #[test]
+    fn test_format_forecast() {
+        assert_eq!(format_forecast(150.0, None), "150");
+        assert_eq!(format_forecast(152.5, None), "152.5");
+        assert_eq!(format_forecast(150.0, Some(1)), "150.0");
+        assert_eq!(format_forecast(152.46, Some(1)), "152.5");
+        assert_eq!(format_forecast(152.46, Some(0)), "152");
+    }
+
+    #[test]
+    fn test_link_message() {
+        let message = LinkState::Reconnecting.message(LINK_TOPIC);
+        assert_eq!(message.topic, "carbon/intensity/mqtt_link");
+        assert_eq!(message.payload, b"reconnecting");
+        assert!(message.retain);
+    }
+
+    #[test]
+    fn test_route_incoming() {
+        let topics = Topics::default();
+        assert_eq!(route_incoming(GET_TOPIC, &topics), Incoming::GetLatest);
+        assert_eq!(route_incoming(STATE_TOPIC, &topics), Incoming::Echo);
+        assert_eq!(route_incoming(FORECAST_TOPIC, &topics), Incoming::Unknown);
+        assert_eq!(
+            route_incoming("carbon/intensity/get/", &topics),
+            Incoming::Unknown
+        );
+
+        let topics = Topics {
+            instance: Some("pi".to_string()),
+        };
+        assert_eq!(
+            route_incoming("carbon/intensity/pi/get", &topics),
+            Incoming::GetLatest
+        );
+        assert_eq!(route_incoming(GET_TOPIC, &topics), Incoming::Unknown);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let vars: std::collections::HashMap<_, _> = [
+            ("APP_MQTT_PASSWORD", "hunter2"),
+            ("APP_TWITTER_ACCESS_TOKEN", "token2"),
+            ("CARBON_MQTT_USER", "ignored"),
+        ]
+        .into_iter()
+        .collect();
+        let mut config = parse_config("");
+        config.apply_env_overrides("APP_", |name| vars.get(name).map(|v| v.to_string()));
+        assert_eq!(config.mqtt.password, "hunter2");
+        assert_eq!(config.twitter_access_token, "token2");
+        assert_eq!(config.mqtt.user, "user");
+        assert_eq!(config.twitter_consumer_key, "key");
+    }
+
+    #[test]
+    fn test_regions_flag() {
+        use structopt::StructOpt;
+
+        let opt = Opt::from_iter_safe(["carbon-alert", "--regions", "13, 16,3,13", "config.ron"])
+            .unwrap();
+        let mut config = parse_config("regions: [1, 2]");
+        config.override_regions(&opt.regions);
+        assert_eq!(config.primary_region().unwrap(), RegionId::London);
+        assert_eq!(
+            config.regions,
+            [RegionId::Scotland, RegionId::NorthWestEngland]
+        );
+
+        assert!(Opt::from_iter_safe(["carbon-alert", "--regions", "13,18", "config.ron"]).is_err());
+        assert_eq!(
+            parse_region("0").unwrap_err(),
+            "Unknown region 0, expected 1 to 17"
+        );
+        assert!(parse_region("london").is_err());
+    }
+
+    #[test]
+    fn test_verbose_flag() {
+        use structopt::StructOpt;
+
+        let opt = Opt::from_iter_safe(["carbon-alert", "config.ron"]).unwrap();
+        assert_eq!(log_filter(opt.verbose), "info");
+        let opt = Opt::from_iter_safe(["carbon-alert", "-vv", "config.ron"]).unwrap();
+        assert_eq!(log_filter(opt.verbose), "info,carbon_alert=trace");
+        let opt =
+            Opt::from_iter_safe(["carbon-alert", "-v", "-v", "-v", "-v", "config.ron"]).unwrap();
+        assert_eq!(log_filter(opt.verbose), "trace");
+    }
+
+    #[test]
+    fn test_debounce() {
+        let start = Instant::now();
+        let mut alerts = AlertState::new(start, Duration::from_secs(0)).with_debounce(3);
+        let mut poll = 0;
+        let mut notifies = |index| {
+            poll += 1;
+            let intensity = IntensityResponse {
+                index,
+                forecast: GramsPerKwh(0),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            };
+            alerts
+                .decide(&intensity, start + Duration::from_secs(poll))
+                .is_some()
+        };
+
+        assert!(!notifies(Intensity::Low));
+        assert!(!notifies(Intensity::Low));
+        assert!(notifies(Intensity::Low));
+        // A one-reading blip is ignored.
+        assert!(!notifies(Intensity::High));
+        assert!(!notifies(Intensity::Low));
+        // A blip to another index starts the count over.
+        assert!(!notifies(Intensity::High));
+        assert!(!notifies(Intensity::High));
+        assert!(!notifies(Intensity::VeryHigh));
+        assert!(!notifies(Intensity::High));
+        assert!(!notifies(Intensity::High));
+        // Only a sustained change fires.
+        assert!(notifies(Intensity::High));
+        assert!(!notifies(Intensity::High));
+    }
+
+    #[test]
+    fn test_rate_limit_pause() {
+        use chrono::TimeZone;
+        let now = chrono::Utc.timestamp(1_639_412_000, 0);
+        assert_eq!(
+            rate_limit_pause(1_639_412_900, now),
+            Duration::from_secs(900)
+        );
+        // A reset in the past means we can go ahead right away.
+        assert_eq!(rate_limit_pause(1_639_411_000, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_schedule_text() {
+        use chrono::TimeZone;
+        let config = try_parse_config(
+            "startup_splay_secs: 30, startup_quiet_secs: 600, debounce_readings: 2, daily_bar_hour: Some(7),",
+        )
+        .unwrap();
+        let now = chrono::Utc.ymd(2021, 12, 13).and_hms(16, 0, 0);
+        insta::assert_snapshot!(schedule_text(&config, now));
+    }
+
+    #[test]
+    fn test_splay() {
+        assert_eq!(splay(0), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(splay(5) <= Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_truncate_graphemes() {
+        let fits = "a".repeat(BLUESKY_MAX_GRAPHEMES);
+        assert_eq!(truncate_graphemes(&fits, BLUESKY_MAX_GRAPHEMES), fits);
+
+        let long = "a".repeat(BLUESKY_MAX_GRAPHEMES + 1);
+        let truncated = truncate_graphemes(&long, BLUESKY_MAX_GRAPHEMES);
+        assert_eq!(truncated.chars().count(), BLUESKY_MAX_GRAPHEMES);
+        assert!(truncated.ends_with('…'));
+
+        // A flag is two characters, but a single grapheme.
+        assert_eq!(truncate_graphemes("🇬🇧🇬🇧", 2), "🇬🇧🇬🇧");
+        assert_eq!(truncate_graphemes("🇬🇧🇬🇧🇬🇧", 2), "🇬🇧…");
+    }
+
+    #[test]
+    fn test_xrpc_error() {
+        let body: XrpcErrorResponse =
+            serde_json::from_str(r#"{"error":"ExpiredToken","message":"Token has expired"}"#)
+                .unwrap();
+        let e = XrpcError::Response {
+            status: reqwest::StatusCode::BAD_REQUEST,
+            body,
+        };
+        assert!(e.is_expired_token());
+        assert_eq!(
+            e.to_string(),
+            "400 Bad Request ExpiredToken: Token has expired"
+        );
+    }
+
+    #[test]
+    fn test_api_headers() {
+        let config =
+            try_parse_config(r#"api_headers: Some({"X-Api-Key": "secret-key"}),"#).unwrap();
+        config.validate().unwrap();
+        let headers = config.api_header_map().unwrap();
+        assert_eq!(headers["x-api-key"], "secret-key");
+        assert!(!format!("{:?}", headers).contains("secret-key"));
+
+        // Authenticating proxies usually want a bearer token.
+        let config =
+            try_parse_config(r#"api_headers: Some({"Authorization": "Bearer secret-token"}),"#)
+                .unwrap();
+        config.validate().unwrap();
+        let headers = config.api_header_map().unwrap();
+        assert_eq!(
+            headers[reqwest::header::AUTHORIZATION],
+            "Bearer secret-token"
+        );
+        assert!(headers[reqwest::header::AUTHORIZATION].is_sensitive());
+
+        let config =
+            try_parse_config(r#"api_headers: Some({"X Api Key": "secret-key"}),"#).unwrap();
+        assert!(config.validate().is_err());
+        let config =
+            try_parse_config(r#"api_headers: Some({"X-Api-Key": "secret\nkey"}),"#).unwrap();
+        let e = config.validate().unwrap_err().to_string();
+        assert!(!e.contains("secret"), "{}", e);
+    }
+
+    #[test]
+    fn test_panic_report() {
+        let config = try_parse_config("report_panics: true,").unwrap();
+        assert!(config.validate().is_err());
+        let config = try_parse_config(
+            r#"report_panics: true, alert_webhook_url: Some("http://localhost/hook"),"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(
+            panic_report("pi", &"panicked at 'oops'", &"0: main"),
+            serde_json::json!({"text": "carbon-alert on pi: panicked at 'oops'\n\n0: main"})
+        );
+    }
+
+    #[test]
+    fn test_bluesky_config() {
+        let config = try_parse_config(r#"bluesky_handle: Some("carbon.bsky.social"),"#).unwrap();
+        assert!(config.validate().is_err());
+        let config = try_parse_config(
+            r#"bluesky_handle: Some("carbon.bsky.social"), bluesky_app_password: "app-password","#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let bluesky = Bluesky::new(&config, reqwest::Client::new()).unwrap();
+        assert_eq!(bluesky.pds_url, "https://bsky.social");
+    }
+
+    #[test]
+    fn test_descriptions() {
+        let config = try_parse_config(
+            r#"describe_index: true, descriptions: {"very high": "Put the kettle down"},"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.description(Intensity::VeryHigh),
+            "Put the kettle down"
+        );
+        assert_eq!(
+            config.description(Intensity::High),
+            "Try to wait if you can"
+        );
+
+        let reading = reading(RegionId::London, "London", 325);
+        assert_eq!(
+            tweet_text(
+                &reading,
+                &Alert::Changed,
+                notification_ctx(&config, &reading, None)
+            ),
+            "The current carbon intensity for London is VeryHigh with approximately 325 gCO2/KWh. \
+             Put the kettle down."
+        );
+    }
+
+    #[test]
+    fn test_severity_override() {
+        let config = try_parse_config(
+            r##"severity: {"very high": (template: Some("🚨 {region} is {index}!"), twitter_tags: ["#carbon", "@grid"])},"##,
+        )
+        .unwrap();
+        let text = |forecast| {
+            let reading = reading(RegionId::London, "London", forecast);
+            let ctx = RenderCtx {
+                hashtags: config.tweet_tags(reading.intensity.index),
+                ..notification_ctx(&config, &reading, None)
+            };
+            tweet_text(&reading, &Alert::Changed, ctx)
+        };
+        assert_eq!(text(325), "🚨 London is VeryHigh! #carbon @grid");
+        assert_eq!(
+            text(250),
+            "The current carbon intensity for London is High with approximately 250 gCO2/KWh."
+        );
+
+        // Rate alerts keep their own template.
+        let reading = reading(RegionId::London, "London", 325);
+        let rising = Alert::Rising {
+            delta: GramsPerKwh(60),
+            window: Duration::from_secs(30 * 60),
+        };
+        assert!(
+            tweet_text(&reading, &rising, notification_ctx(&config, &reading, None))
+                .starts_with("Carbon intensity for London is rising quickly")
+        );
+
+        // They share the hashtags' length check, and repeats are dropped.
+        let config = try_parse_config(&format!(
+            r##"severity: {{"very high": (template: Some("{}"), twitter_tags: ["#Carbon", "@grid"])}}, hashtags: {{"very high": ["carbon"]}},"##,
+            "x".repeat(TWEET_MAX_GRAPHEMES - 8),
+        ))
+        .unwrap();
+        let ctx = RenderCtx {
+            hashtags: config.tweet_tags(reading.intensity.index),
+            ..notification_ctx(&config, &reading, None)
+        };
+        assert_eq!(ctx.hashtags, ["#carbon", "@grid"]);
+        let text = tweet_text(&reading, &Alert::Changed, ctx);
+        assert!(text.ends_with("x #carbon"), "{}", text);
+    }
+
+    #[test]
+    fn test_hashtags() {
+        let config = try_parse_config(
+            r##"hashtags: {"very low": ["LowCarbon", "#lowcarbon", "#Green_Day"], "very high": ["CarbonAlert"]},"##,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let text = |forecast| {
+            let reading = reading(RegionId::London, "London", forecast);
+            tweet_text(
+                &reading,
+                &Alert::Changed,
+                notification_ctx(&config, &reading, None),
+            )
+        };
+        assert_eq!(
+            text(20),
+            "The current carbon intensity for London is VeryLow with approximately 20 gCO2/KWh. \
+             #LowCarbon #Green_Day"
+        );
+        assert!(text(400).ends_with("gCO2/KWh. #CarbonAlert"));
+        // Indexes without any get none.
+        assert!(text(250).ends_with("gCO2/KWh."));
+
+        for tag in ["Low-Carbon", "2021", "#", "low carbon"] {
+            let config =
+                try_parse_config(&format!(r#"hashtags: {{"low": [{:?}]}},"#, tag)).unwrap();
+            assert!(config.validate().is_err(), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn test_hashtags_length() {
+        let reading = reading(RegionId::London, "London", 20);
+        let ctx = RenderCtx {
+            hashtags: (0..30).map(|n| format!("#Tag{:02}", n)).collect(),
+            ..RenderCtx::default()
+        };
+        let text = tweet_text(&reading, &Alert::Changed, ctx);
+        assert!(text.chars().count() <= TWEET_MAX_GRAPHEMES, "{}", text);
+        assert!(text.ends_with(" #Tag26 #Tag27"), "{}", text);
+        // Bluesky has room for a few more.
+        let ctx = RenderCtx {
+            hashtags: (0..30).map(|n| format!("#Tag{:02}", n)).collect(),
+            ..RenderCtx::default()
+        };
+        let text = post_text(&reading, &Alert::Changed, ctx, BLUESKY_MAX_GRAPHEMES);
+        assert!(text.chars().count() <= BLUESKY_MAX_GRAPHEMES, "{}", text);
+        assert!(text.ends_with(" #Tag29"), "{}", text);
+
+        // The placeholder is empty without any.
+        assert_eq!(
+            render(
+                "{region}{hashtags}",
+                &reading.intensity,
+                "London",
+                &RenderCtx::default()
+            ),
+            "London"
+        );
+    }
+
+    #[test]
+    fn test_greenness_score() {
+        let max = GramsPerKwh(400);
+        assert_eq!(greenness_score(GramsPerKwh(0), max), 100);
+        assert_eq!(greenness_score(GramsPerKwh(200), max), 50);
+        assert_eq!(greenness_score(GramsPerKwh(399), max), 0);
+        assert_eq!(greenness_score(GramsPerKwh(400), max), 0);
+        assert_eq!(greenness_score(GramsPerKwh(1000), max), 0);
+
+        assert_eq!(
+            tweet_text(
+                &reading(RegionId::London, "London", 100),
+                &Alert::Changed,
+                RenderCtx {
+                    score_max: Some(max),
+                    ..RenderCtx::default()
+                }
+            ),
+            "The current carbon intensity for London is Low with approximately 100 gCO2/KWh. \
+             Greenness score: 75/100."
+        );
+    }
+
+    #[test]
+    fn test_grams_per_kwh() {
+        assert_eq!(GramsPerKwh(435).to_string(), "435 gCO2/kWh");
+        assert_eq!(
+            GramsPerKwh(200).saturating_sub(GramsPerKwh(150)),
+            GramsPerKwh(50)
+        );
+        assert_eq!(
+            GramsPerKwh(150).saturating_sub(GramsPerKwh(200)),
+            GramsPerKwh(0)
+        );
+        assert_eq!(GramsPerKwh::round(152.5), GramsPerKwh(153));
+        // It's a bare number on the wire.
+        assert_eq!(serde_json::to_string(&GramsPerKwh(435)).unwrap(), "435");
+    }
+
+    #[test]
+    fn test_render() {
+        let intensity = IntensityResponse {
+            index: Intensity::VeryHigh,
+            forecast: GramsPerKwh(435),
+            actual: None,
+            forecast_low: None,
+            forecast_high: None,
+        };
+        let ctx = RenderCtx {
+            delta: Some(GramsPerKwh(60)),
+            window: Some(Duration::from_secs(30 * 60)),
+            ..RenderCtx::default()
+        };
+        assert_eq!(
+            render(
+                "{region}: {index} at {forecast}, up {delta} in {window_mins}",
+                &intensity,
+                "London",
+                &ctx
+            ),
+            "London: VeryHigh at 435, up 60 in 30"
+        );
+        assert_eq!(
+            render(
+                CHANGED_TEMPLATE,
+                &intensity,
+                "London",
+                &RenderCtx::default()
+            ),
+            "The current carbon intensity for London is VeryHigh with approximately 435 gCO2/KWh."
+        );
+        // Unknown placeholders, ones without a value and stray braces are
+        // kept.
+        assert_eq!(
+            render(
+                "{nope} {delta} {} {forecast",
+                &intensity,
+                "London",
+                &RenderCtx::default()
+            ),
+            "{nope} {delta} {} {forecast"
+        );
+    }
+
+    #[test]
+    fn test_forecast_range() {
+        let mut intensity: IntensityResponse = serde_json::from_str(
+            r#"{"index": "high", "forecast": 250, "forecast_low": 220, "forecast_high": 280}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            intensity.forecast_range(),
+            Some((GramsPerKwh(220), GramsPerKwh(280)))
+        );
+        assert_eq!(
+            render(
+                "{forecast_range}",
+                &intensity,
+                "London",
+                &RenderCtx::default()
+            ),
+            "220-280"
+        );
+        assert!(serde_json::to_string(&intensity)
+            .unwrap()
+            .contains(r#""forecast_low":220,"forecast_high":280"#));
+
+        // The range only applies to the forecast it came with.
+        intensity.replace_forecast(GramsPerKwh(240));
+        assert_eq!(intensity.forecast_range(), None);
+        assert_eq!(
+            serde_json::to_string(&intensity).unwrap(),
+            r#"{"index":"High","forecast":240}"#
+        );
+    }
+
+    #[test]
+    fn test_render_all_placeholders() {
+        const ALL: &str = "{region} | {index} | {forecast} | {delta} | {window_mins} | \
+                           {vs_weekly_average} | {score} | {description}";
+        let intensity = IntensityResponse {
+            index: Intensity::High,
+            forecast: GramsPerKwh(250),
+            actual: None,
+            forecast_low: None,
+            forecast_high: None,
+        };
+        let full = || RenderCtx {
+            delta: Some(GramsPerKwh(60)),
+            window: Some(Duration::from_secs(30 * 60)),
+            weekly_average: Some(GramsPerKwh(200)),
+            score_max: Some(GramsPerKwh(400)),
+            description: Some(Intensity::High.description().to_string()),
+            ..RenderCtx::default()
+        };
+        let cases = [
+            ("all", ALL, full()),
+            ("no context", ALL, RenderCtx::default()),
+        ];
+        let rendered: Vec<_> = cases
+            .iter()
+            .map(|(name, template, ctx)| {
+                format!("{}: {}", name, render(template, &intensity, "London", ctx))
+            })
+            .collect();
+        insta::assert_snapshot!(rendered.join("\n"));
+
+        // Anything else is rejected at startup.
+        assert_eq!(unknown_placeholder(ALL), None);
+        assert_eq!(unknown_placeholder("{region} {time}"), Some("{time}"));
+        for extra in [
+            r#"recovery_template: Some("{region} at {time}"),"#,
+            r#"severity: {"high": (template: Some("{trend} in {region}"))},"#,
+        ] {
+            let err = try_parse_config(extra).unwrap().validate().unwrap_err();
+            assert!(
+                err.to_string().starts_with("Unknown placeholder"),
+                "{}",
+                err
+            );
+        }
+        assert!(
+            try_parse_config(r#"recovery_template: Some("{region} {hashtags}"),"#)
+                .unwrap()
+                .validate()
+                .is_ok()
+        );
+    }
+
+    /// Compares polling with a fresh client each time to polling with a
+    /// shared one. Needs network access, so run it by hand with
+    /// `cargo test -- --ignored --nocapture bench_connection_reuse`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_connection_reuse() {
+        const POLLS: u32 = 5;
+        let config = parse_config("");
+        let limiter = RateLimiter::default();
+        let regions = [RegionId::London];
+
+        let started = Instant::now();
+        for _ in 0..POLLS {
+            let client = config.http_client().unwrap();
+            fetch_regions(
+                &client,
+                &limiter,
+                &config.api_base_url,
+                &regions,
+                1,
+                config.slot(),
+            )
+            .await
+            .unwrap();
+        }
+        let fresh = started.elapsed() / POLLS;
+
+        let client = config.http_client().unwrap();
+        // Warm up the connection.
+        fetch_regions(
+            &client,
+            &limiter,
+            &config.api_base_url,
+            &regions,
+            1,
+            config.slot(),
+        )
+        .await
+        .unwrap();
+        let started = Instant::now();
+        for _ in 0..POLLS {
+            fetch_regions(
+                &client,
+                &limiter,
+                &config.api_base_url,
+                &regions,
+                1,
+                config.slot(),
+            )
+            .await
+            .unwrap();
+        }
+        let shared = started.elapsed() / POLLS;
+
+        println!(
+            "Per poll: {:?} with a fresh client, {:?} with a shared one.",
+            fresh, shared
+        );
+    }
+
+    #[test]
+    fn test_example_config() {
+        let config: Config = ron::de::from_str(include_str!("../config.ron.example")).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.primary_region().unwrap(), RegionId::London);
+        // Upgrades keep the payloads that subscribers already parse.
+        assert_eq!(config.mqtt.payload_schema, PayloadSchema::V1);
+    }
+
+    #[test]
+    fn test_country() {
+        let mut config = parse_config("country: Some(Wales)");
+        assert_eq!(config.primary_region().unwrap(), RegionId::London);
+        config.region = None;
+        assert!(config.validate().is_ok());
+        assert_eq!(config.primary_region().unwrap(), RegionId::Wales);
+        config.country = None;
+        assert!(config.validate().is_err());
+
+        assert_eq!(
+            region_url(DEFAULT_API_BASE_URL, RegionId::Wales),
+            "https://api.carbonintensity.org.uk/regional/wales"
+        );
+        assert_eq!(
+            region_url("http://relay:8080", RegionId::London),
+            "http://relay:8080/regional/regionid/13"
+        );
+    }
+
+    #[test]
+    fn test_nation_urls() {
+        assert_eq!(
+            region_url(DEFAULT_API_BASE_URL, RegionId::England),
+            "https://api.carbonintensity.org.uk/regional/england"
+        );
+        assert_eq!(
+            region_url(DEFAULT_API_BASE_URL, RegionId::Scotland),
+            "https://api.carbonintensity.org.uk/regional/scotland"
+        );
+        assert_eq!(
+            region_range_url(
+                DEFAULT_API_BASE_URL,
+                RegionId::England,
+                "2021-12-13T16:30Z/fw24h"
+            ),
+            "https://api.carbonintensity.org.uk/regional/intensity/2021-12-13T16:30Z/fw24h/regionid/15"
+        );
+    }
+
+    #[test]
+    fn test_sources() {
+        let config = try_parse_config("").unwrap();
+        assert_eq!(config.notification_source(), ValueSource::Forecast);
+        assert_eq!(config.publish_source(), ValueSource::Forecast);
+
+        // Without sources of their own, both follow the smoothing.
+        let config = try_parse_config("smoothing_window: Some(3),").unwrap();
+        assert_eq!(config.notification_source(), ValueSource::Smoothed);
+        assert_eq!(config.publish_source(), ValueSource::Smoothed);
+
+        let config =
+            try_parse_config("notification_source: Some(Forecast), smoothing_window: Some(3),")
+                .unwrap();
+        assert_eq!(config.notification_source(), ValueSource::Forecast);
+        assert_eq!(config.publish_source(), ValueSource::Smoothed);
+        // Only the national endpoints have measured values.
+        assert!(try_parse_config("notification_source: Some(Actual),").is_err());
+
+        let config = try_parse_config("publish_source: Some(Smoothed),").unwrap();
+        assert!(config.validate().is_err());
+
+        let polled = [reading(RegionId::England, "England", 266)];
+        let smoothed = [reading(RegionId::England, "England", 250)];
+        let forecast = |source, smoothed| sourced(&polled, smoothed, source)[0].intensity.forecast;
+        assert_eq!(
+            forecast(ValueSource::Forecast, Some(&smoothed[..])),
+            GramsPerKwh(266)
+        );
+        assert_eq!(
+            forecast(ValueSource::Smoothed, Some(&smoothed[..])),
+            GramsPerKwh(250)
+        );
+        assert_eq!(forecast(ValueSource::Smoothed, None), GramsPerKwh(266));
+    }
+
+    #[test]
+    fn test_publish_filter() {
+        let snapshot = |forecast, other| {
+            snapshot(
+                reading(RegionId::London, "London", forecast),
+                vec![reading(RegionId::Wales, "Wales", other)],
+            )
+        };
+
+        let mut always = PublishFilter::new(PublishMode::Always);
+        assert!(always.should_publish(&snapshot(100, 50)));
+        assert!(always.should_publish(&snapshot(100, 50)));
+
+        let mut on_change = PublishFilter::new(PublishMode::OnChange);
+        assert!(on_change.should_publish(&snapshot(100, 50)));
+        assert!(!on_change.should_publish(&snapshot(100, 50)));
+        assert!(on_change.should_publish(&snapshot(101, 50)));
+        // A change in another region counts too.
+        assert!(on_change.should_publish(&snapshot(101, 60)));
+        assert!(!on_change.should_publish(&snapshot(101, 60)));
+    }
+
+    #[test]
+    fn test_stale_snapshot() {
+        use chrono::TimeZone;
+
+        // The slot ends at 17:00.
+        let snapshot = snapshot(reading(RegionId::London, "London", 100), Vec::new());
+        let hour = Duration::from_secs(60 * 60);
+        let at = |h, m| chrono::Utc.ymd(2021, 12, 13).and_hms(h, m, 0);
+        assert!(!snapshot.is_stale(at(16, 45), hour));
+        assert!(!snapshot.is_stale(at(18, 0), hour));
+        assert!(snapshot.is_stale(at(18, 1), hour));
+
+        // The state says so, and when it was fetched.
+        let topics = Topics::default();
+        let encoding = Encoding {
+            format: PayloadFormat::Json,
+            schema: PayloadSchema::V2,
+        };
+        let state = |now| {
+            let message = state_message(&snapshot, &topics, encoding, hour, now).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&message.payload).unwrap()
+        };
+        assert_eq!(state(at(16, 45))["stale"], false);
+        let stale = state(at(18, 1));
+        assert_eq!(stale["stale"], true);
+        assert_eq!(stale["fetched_at"], "2021-12-13T16:40:00Z");
+    }
+
+    #[tokio::test]
+    async fn test_suppress_stale_publish() {
+        use chrono::TimeZone;
+
+        let snapshot = snapshot(reading(RegionId::London, "London", 100), Vec::new());
+        let at = |h, m| chrono::Utc.ymd(2021, 12, 13).and_hms(h, m, 0);
+        let published = |config: &Config, now| {
+            let recorder = Recorder::default();
+            let mut filter = PublishFilter::new(config.mqtt.publish_mode);
+            let config = config.clone();
+            let snapshot = snapshot.clone();
+            async move {
+                let published = publish_if_due(
+                    &recorder,
+                    &config,
+                    &config.topics(),
+                    &mut filter,
+                    &snapshot,
+                    now,
+                )
+                .await
+                .unwrap();
+                (published, recorder.0.into_inner().unwrap().len())
+            }
+        };
+
+        // Stale readings still go out by default...
+        let config = try_parse_config("").unwrap();
+        assert!(published(&config, at(18, 1)).await.0);
+        // ...but not when suppressed, and nothing at all is published.
+        let mut config = config;
+        config.mqtt.suppress_stale_publish = true;
+        assert_eq!(published(&config, at(18, 1)).await, (false, 0));
+        assert!(published(&config, at(17, 30)).await.0);
+    }
+
+    #[test]
+    fn test_instance_topics() {
+        let topics = Topics {
+            instance: Some("pi".to_string()),
+        };
+        assert_eq!(topics.get(STATE_TOPIC), "carbon/intensity/pi");
+        assert_eq!(topics.get(FORECAST_TOPIC), "carbon/intensity/pi/forecast");
+        assert_eq!(topics.get("homeassistant/status"), "homeassistant/status");
+        assert_eq!(Topics::default().get(FORECAST_TOPIC), FORECAST_TOPIC);
+
+        let config = parse_config(r#"instance_name: Some("pi")"#);
+        assert_eq!(config.topics().get(STATE_TOPIC), STATE_TOPIC);
+
+        let tagged = Tagged {
+            instance: "pi",
+            payload: &reading(RegionId::London, "London", 100),
+        };
+        let json = serde_json::to_value(&tagged).unwrap();
+        assert_eq!(json["instance"], "pi");
+        assert_eq!(json["shortname"], "London");
+    }
+
+    #[test]
+    fn test_notifier_thresholds() {
+        let config = try_parse_config(
+            r#"
+            startup_quiet_secs: 0,
+            same_condition_cooldown_secs: Some(3600),
+            twitter: (notify_threshold: Some("very high"), delivery: Every),
+            bluesky: (notify_threshold: Some("high"), same_condition_cooldown_secs: Some(0)),
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.twitter.delivery, Delivery::Every);
+        assert_eq!(config.bluesky.delivery, Delivery::Latest);
+        let start = Instant::now();
+        let mut twitter = AlertState::from_config(&config, &config.twitter, start);
+        let mut bluesky = AlertState::from_config(&config, &config.bluesky, start);
+        let mut everything = AlertState::from_config(&config, &NotifierOverrides::default(), start);
+
+        let readings = [
+            Intensity::Moderate,
+            Intensity::High,
+            Intensity::VeryHigh,
+            Intensity::High,
+            Intensity::VeryHigh,
+        ];
+        let notified = |alerts: &mut AlertState| {
+            readings
+                .iter()
+                .enumerate()
+                .filter_map(|(i, &index)| {
+                    let intensity = IntensityResponse {
+                        index,
+                        forecast: GramsPerKwh(0),
+                        actual: None,
+                        forecast_low: None,
+                        forecast_high: None,
+                    };
+                    let now = start + Duration::from_secs(i as u64 * 60);
+                    alerts.decide(&intensity, now).map(|_| index)
+                })
+                .collect::<Vec<_>>()
+        };
+        // The second VeryHigh falls within the global cooldown, as the High
+        // in between wasn't notified about.
+        assert_eq!(notified(&mut twitter), [Intensity::VeryHigh]);
+        assert_eq!(
+            notified(&mut bluesky),
+            [
+                Intensity::High,
+                Intensity::VeryHigh,
+                Intensity::High,
+                Intensity::VeryHigh
+            ]
+        );
+        // Without a threshold, every change of index goes out.
+        assert_eq!(notified(&mut everything), readings);
+    }
+
+    #[test]
+    fn test_same_condition_cooldown() {
+        let start = Instant::now();
+        let window = Duration::from_secs(30 * 60);
+        let mut alerts = AlertState::new(start, Duration::from_secs(0))
+            .with_rate_alert(GramsPerKwh(50), window)
+            .with_cooldown(Duration::from_secs(60 * 60));
+        let mut decide = |index, forecast, mins: u64| {
+            let intensity = IntensityResponse {
+                index,
+                forecast: GramsPerKwh(forecast),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            };
+            alerts.decide(&intensity, start + Duration::from_secs(mins * 60))
+        };
+
+        assert_eq!(decide(Intensity::Moderate, 150, 0), Some(Alert::Changed));
+        // Rising at the same index within the cooldown is held back.
+        assert_eq!(decide(Intensity::Moderate, 200, 10), None);
+        // A different index fires right away...
+        assert_eq!(decide(Intensity::High, 250, 20), Some(Alert::Changed));
+        // ...and going back counts as a fresh condition.
+        assert_eq!(decide(Intensity::Moderate, 150, 30), Some(Alert::Changed));
+        assert_eq!(decide(Intensity::Moderate, 300, 40), None);
+        assert_eq!(decide(Intensity::Moderate, 300, 80), None);
+        // Once the cooldown is over, the same index can fire again.
+        assert_eq!(
+            decide(Intensity::Moderate, 355, 95),
+            Some(Alert::Rising {
+                delta: GramsPerKwh(55),
+                window
+            })
+        );
+    }
+
+    #[test]
+    fn test_recovery() {
+        let start = Instant::now();
+        let mut alerts = AlertState::new(start, Duration::ZERO)
+            .with_threshold(Intensity::High)
+            .with_recovery(Intensity::Low);
+        let mut decide = |index, mins: u64| {
+            let intensity = IntensityResponse {
+                index,
+                forecast: GramsPerKwh(100),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            };
+            alerts.decide(&intensity, start + Duration::from_secs(mins * 60))
+        };
+
+        // Starting out low isn't a recovery.
+        assert_eq!(decide(Intensity::Low, 0), None);
+        assert_eq!(decide(Intensity::High, 30), Some(Alert::Changed));
+        assert_eq!(decide(Intensity::Moderate, 60), None);
+        // Dropping to low fires, despite the threshold...
+        assert_eq!(decide(Intensity::Low, 90), Some(Alert::Recovered));
+        // ...but only once while it stays there.
+        assert_eq!(decide(Intensity::Low, 120), None);
+        assert_eq!(decide(Intensity::VeryLow, 150), None);
+        assert_eq!(decide(Intensity::Moderate, 180), None);
+        assert_eq!(decide(Intensity::VeryLow, 210), Some(Alert::Recovered));
+
+        let config = try_parse_config(
+            r#"recovery_notify: true, recovery_template: Some("{region} is {index} again"),"#,
+        )
+        .unwrap();
+        let reading = reading(RegionId::London, "London", 20);
+        assert_eq!(
+            tweet_text(
+                &reading,
+                &Alert::Recovered,
+                notification_ctx(&config, &reading, None)
+            ),
+            "London is VeryLow again"
+        );
+        assert_eq!(
+            tweet_text(&reading, &Alert::Recovered, RenderCtx::default()),
+            "All clear: carbon intensity for London is down to VeryLow with approximately 20 gCO2/KWh. \
+             A great time to use electricity."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff_secs: 0,
+        };
+        let mut calls = 0;
+        let res: Result<(), String> = retry(
+            &policy,
+            || {
+                calls += 1;
+                async { Err("down".to_string()) }
+            },
+            |_| true,
+        )
+        .await;
+        assert_eq!(res, Err("down".to_string()));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let res = retry(
+            &policy,
+            || {
+                calls += 1;
+                let n = calls;
+                async move {
+                    if n < 2 {
+                        Err("flaky".to_string())
+                    } else {
+                        Ok(n)
+                    }
+                }
+            },
+            |_| true,
+        )
+        .await;
+        assert_eq!(res, Ok(2));
+
+        // Errors that aren't retryable are final.
+        let mut calls = 0;
+        let res: Result<(), String> = retry(
+            &policy,
+            || {
+                calls += 1;
+                async { Err("rate limited".to_string()) }
+            },
+            |_| false,
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    /// Keeps what's published, instead of sending it anywhere.
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<Message>>);
+
+    impl MqttPublisher for Recorder {
+        fn publish(
+            &self,
+            message: Message,
+        ) -> futures_util::future::BoxFuture<'_, anyhow::Result<()>> {
+            self.0.lock().unwrap().push(message);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_snapshot() {
+        let snapshot = Snapshot {
+            seq: 1,
+            ..snapshot(
+                reading(RegionId::London, "London", 100),
+                vec![reading(RegionId::SouthWales, "South Wales", 300)],
+            )
+        };
+        let recorder = Recorder::default();
+        let config = try_parse_config("").unwrap();
+        publish_snapshot(&recorder, &config, &config.topics(), &snapshot)
+            .await
+            .unwrap();
+        let topics: Vec<_> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.topic.clone())
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                STATE_TOPIC,
+                LEVEL_TOPIC,
+                DATA_AGE_TOPIC,
+                "carbon/intensity/7"
+            ]
+        );
+
+        // The optional extras only go out when configured.
+        let recorder = Recorder::default();
+        let config = try_parse_config("score_max: Some(400), describe_index: true,").unwrap();
+        publish_snapshot(&recorder, &config, &config.topics(), &snapshot)
+            .await
+            .unwrap();
+        let published = recorder.0.into_inner().unwrap();
+        let extras: Vec<_> = published
+            .iter()
+            .filter(|m| [SCORE_TOPIC, DESCRIPTION_TOPIC].contains(&m.topic.as_str()))
+            .map(|m| {
+                (
+                    m.topic.as_str(),
+                    String::from_utf8_lossy(&m.payload).into_owned(),
+                    m.retain,
+                )
+            })
+            .collect();
+        assert_eq!(
+            extras,
+            [
+                (SCORE_TOPIC, "75".to_string(), true),
+                (
+                    DESCRIPTION_TOPIC,
+                    "Good time to use electricity".to_string(),
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_says_goodbye() {
+        let config = try_parse_config("").unwrap();
+        let topics = config.topics();
+        let (requests_tx, requests_rx) = flume::bounded(10);
+        let client = MqttClient::V3(rumqttc::AsyncClient::from_senders(requests_tx));
+        let (tx, mut rx) = SnapshotTx::new();
+        let mut notifiers = tokio::task::JoinSet::new();
+        notifiers.spawn(async move {
+            while next_snapshot(&mut rx, "MQTT", &mut 0).await.is_some() {}
+            say_goodbye(&client, &config, &topics)
+                .await
+                .map(|()| "MQTT")
+                .map_err(|e| NotifierFailed {
+                    name: "MQTT",
+                    error: e.to_string(),
+                })
+        });
+
+        let started = Instant::now();
+        shutdown(tx, notifiers).await.unwrap();
+        assert!(started.elapsed() < SHUTDOWN_TIMEOUT);
+
+        let requests: Vec<_> = requests_rx.drain().collect();
+        let published: Vec<_> = requests
+            .iter()
+            .filter_map(|r| match r {
+                rumqttc::Request::Publish(p) => Some((
+                    p.topic.as_str(),
+                    String::from_utf8_lossy(&p.payload).into_owned(),
+                    p.retain,
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            published,
+            [
+                (LINK_TOPIC, "disconnected".to_string(), true),
+                (POLL_STATUS_TOPIC, "offline".to_string(), true),
+            ]
+        );
+        // Nothing may be queued after the disconnect, as it would be lost.
+        assert!(matches!(
+            requests.last(),
+            Some(rumqttc::Request::Disconnect(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let path = std::env::temp_dir().join(format!(
+            "carbon-alert-dead-letter-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let counters = Counters::default();
+        dead_letter(
+            &counters,
+            Some(path.as_path()),
+            "twitter",
+            "first",
+            "timed out",
+        )
+        .await;
+        dead_letter(
+            &counters,
+            Some(path.as_path()),
+            "twitter",
+            "second",
+            "timed out",
+        )
+        .await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["notifier"], "twitter");
+        assert_eq!(lines[0]["payload"], "first");
+        assert_eq!(lines[0]["error"], "timed out");
+        assert_eq!(lines[1]["payload"], "second");
+    }
+
+    #[tokio::test]
+    async fn test_readings_log() {
+        let path = std::env::temp_dir().join(format!(
+            "carbon-alert-readings-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let snapshot = snapshot(
+            reading(RegionId::London, "London", 435),
+            vec![reading(RegionId::Scotland, "North Scotland", 20)],
+        );
+        let now = chrono::Utc::now();
+        let mut log = ReadingsLog::new(path.clone(), false);
+        log.append(&snapshot, now).await;
+        log.append(&snapshot, now).await;
+        assert!(!log.failing);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["shortname"], "London");
+        assert_eq!(lines[1]["intensity"]["forecast"], 20);
+
+        let mut log = ReadingsLog::new(path.join("unwritable.jsonl"), false);
+        log.append(&snapshot, now).await;
+        assert!(log.failing);
+
+        let log = ReadingsLog::new(PathBuf::from("/var/log/readings.jsonl"), true);
+        assert_eq!(
+            log.path_on(chrono::NaiveDate::from_ymd(2021, 12, 13)),
+            PathBuf::from("/var/log/readings.2021-12-13.jsonl")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_sink() {
+        use chrono::TimeZone;
+
+        let path = std::env::temp_dir().join(format!(
+            "carbon-alert-readings-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut london = reading(RegionId::London, "London", 435);
+        london.generation_mix = vec![
+            FuelShare {
+                fuel: "wind".to_string(),
+                perc: 30.0,
+            },
+            FuelShare {
+                fuel: "gas".to_string(),
+                perc: 70.0,
+            },
+        ];
+        let snapshot = snapshot(
+            london,
+            vec![reading(RegionId::Scotland, "North Scotland", 20)],
+        );
+        let now = chrono::Utc.ymd(2021, 12, 13).and_hms(16, 45, 0);
+        let mut sink = SqliteSink::open(&path, default_renewable_fuels()).unwrap();
+        sink.insert(&snapshot, now).await;
+        assert!(!sink.failing);
+        drop(sink);
+
+        // Reopening doesn't migrate again.
+        let sink = SqliteSink::open(&path, default_renewable_fuels()).unwrap();
+        let conn = sink.conn.lock().unwrap();
+        let mut select = conn
+            .prepare(
+                "SELECT timestamp, region, intensity_index, forecast, renewables_pct FROM readings",
+            )
+            .unwrap();
+        let rows: Vec<(String, u16, String, u32, Option<f64>)> = select
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(select);
+        drop(conn);
+        drop(sink);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        assert_eq!(
+            rows,
+            [
+                (
+                    "2021-12-13T16:45:00Z".to_string(),
+                    13,
+                    "VeryHigh".to_string(),
+                    435,
+                    Some(30.0)
+                ),
+                (
+                    "2021-12-13T16:45:00Z".to_string(),
+                    RegionId::Scotland as u16,
+                    "VeryLow".to_string(),
+                    20,
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poll_floor() {
+        let mut config = parse_config("");
+        config.poll_interval_secs = 5;
+        config.enforce_poll_floor();
+        assert_eq!(config.poll_interval_secs, MIN_POLL_INTERVAL_SECS);
+
+        config.poll_interval_secs = 5;
+        config.allow_aggressive_polling = true;
+        config.enforce_poll_floor();
+        assert_eq!(config.poll_interval_secs, 5);
+
+        let mut config = parse_config("");
+        config.enforce_poll_floor();
+        assert_eq!(config.poll_interval_secs, 120);
+    }
+
+    /// Subscribers depend on these field names and types, so changes to them
+    /// should be deliberate.
+    #[test]
+    fn test_json_payload() {
+        let reading = Reading {
+            intensity: IntensityResponse {
+                index: Intensity::VeryHigh,
+                forecast: GramsPerKwh(435),
+                actual: Some(GramsPerKwh(430)),
+                forecast_low: None,
+                forecast_high: None,
+            },
+            is_estimated: false,
+            raw_forecast: Some(GramsPerKwh(450)),
+            ..reading(RegionId::London, "London", 435)
+        };
+        let message = reading_message(
+            STATE_TOPIC.to_string(),
+            &reading,
+            Encoding {
+                format: PayloadFormat::Json,
+                schema: PayloadSchema::V2,
+            },
+        )
+        .unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&message.payload).unwrap();
+        insta::assert_json_snapshot!(payload);
+    }
+
+    #[test]
+    fn test_level_message() {
+        let snapshot = snapshot(reading(RegionId::London, "London", 435), Vec::new());
+        let topics = Topics {
+            instance: Some("attic".to_string()),
+        };
+        let message = level_message(&snapshot, &topics, LEVEL_TOPIC);
+        assert_eq!(message.topic, "carbon/intensity/attic/level");
+        assert_eq!(message.payload, b"4");
+        assert!(message.retain);
+    }
+
+    #[test]
+    fn test_poll_status() {
+        use chrono::TimeZone;
+
+        let mut snapshot = snapshot(reading(RegionId::London, "London", 435), Vec::new());
+        let during = chrono::Utc.ymd(2021, 12, 13).and_hms(16, 45, 0);
+        let after = chrono::Utc.ymd(2021, 12, 13).and_hms(17, 0, 0);
+        assert_eq!(PollStatus::of(None, false, during), PollStatus::Offline);
+        assert_eq!(
+            PollStatus::of(Some(&snapshot), false, during),
+            PollStatus::Online
+        );
+        assert_eq!(
+            PollStatus::of(Some(&snapshot), false, after),
+            PollStatus::Degraded
+        );
+        assert_eq!(
+            PollStatus::of(Some(&snapshot), true, during),
+            PollStatus::Degraded
+        );
+        snapshot.window = Some(Vec::new());
+        assert_eq!(
+            PollStatus::of(Some(&snapshot), true, during),
+            PollStatus::Online
+        );
+    }
+
+    #[test]
+    fn test_compact_payload() {
+        let reading = reading(RegionId::London, "London", 435);
+        let payload = compact_payload(&reading);
+        assert_eq!(payload[0], Intensity::VeryHigh as u8);
+        assert_eq!(u16::from_be_bytes([payload[1], payload[2]]), 435);
+        assert_eq!(
+            u32::from_be_bytes([payload[3], payload[4], payload[5], payload[6]]),
+            1_639_413_000
+        );
+
+        let message = reading_message(
+            STATE_TOPIC.to_string(),
+            &reading,
+            Encoding {
+                format: PayloadFormat::Index,
+                schema: PayloadSchema::V2,
+            },
+        )
+        .unwrap();
+        assert_eq!(message.payload, [Intensity::VeryHigh as u8]);
+        let message = reading_message(
+            STATE_TOPIC.to_string(),
+            &reading,
+            Encoding {
+                format: PayloadFormat::Json,
+                schema: PayloadSchema::V2,
+            },
+        )
+        .unwrap();
+        assert!(message.json);
+    }
+
+    #[test]
+    fn test_stats() {
+        let j = r#"{"data":[{"from":"2021-12-06T16:30Z","to":"2021-12-13T16:30Z","intensity":{"max":320,"average":250,"min":180,"index":"moderate"}}]}"#;
+        let resp: StatsResponse = serde_json::from_str(j).unwrap();
+        let StatsResponse::Data(items) = resp else {
+            panic!("expected data, got {:?}", resp);
+        };
+        assert_eq!(
+            items[0].intensity,
+            IntensityStats {
+                max: GramsPerKwh(320),
+                average: GramsPerKwh(250),
+                min: GramsPerKwh(180),
+                index: Intensity::Moderate,
+            }
+        );
+
+        assert_eq!(
+            compare_to_average(GramsPerKwh(325), GramsPerKwh(250)).as_deref(),
+            Some("30% above")
+        );
+        assert_eq!(
+            compare_to_average(GramsPerKwh(200), GramsPerKwh(250)).as_deref(),
+            Some("20% below")
+        );
+        assert_eq!(
+            compare_to_average(GramsPerKwh(250), GramsPerKwh(250)).as_deref(),
+            Some("in line with")
+        );
+        assert_eq!(compare_to_average(GramsPerKwh(250), GramsPerKwh(0)), None);
+
+        assert_eq!(
+            tweet_text(
+                &reading(RegionId::London, "London", 325),
+                &Alert::Changed,
+                RenderCtx {
+                    weekly_average: Some(GramsPerKwh(250)),
+                    ..RenderCtx::default()
+                }
+            ),
+            "The current carbon intensity for London is VeryHigh with approximately 325 gCO2/KWh. \
+             That's 30% above the national weekly average."
+        );
+        // Without anything to compare to, the sentence is left out.
+        assert_eq!(
+            tweet_text(
+                &reading(RegionId::London, "London", 325),
+                &Alert::Changed,
+                RenderCtx {
+                    weekly_average: Some(GramsPerKwh(0)),
+                    ..RenderCtx::default()
+                }
+            ),
+            "The current carbon intensity for London is VeryHigh with approximately 325 gCO2/KWh."
+        );
+    }
+
+    #[test]
+    fn test_forecast_window() {
+        let j = r#"
+{
+    "data": {
+        "regionid": 13,
+        "dnoregion": "UKPN London",
+        "shortname": "London",
+        "data": [
+            {
+                "from": "2021-12-13T16:30Z",
+                "to": "2021-12-13T17:00Z",
+                "intensity": { "forecast": 435, "index": "very high" }
+            },
+            {
+                "from": "2021-12-13T17:00Z",
+                "to": "2021-12-13T17:30Z",
+                "intensity": { "forecast": 120, "index": "low" }
+            }
+        ]
+    }
+}
+        "#;
+        let resp: RegionalHistoryResponse = serde_json::from_str(j).unwrap();
+        let window = history_readings(resp, RegionId::London).unwrap();
+        let slots: Vec<WindowSlot> = window.iter().map(WindowSlot::from).collect();
+        assert_eq!(
+            serde_json::to_value(&slots).unwrap(),
+            serde_json::json!([
+                {"from": "2021-12-13T16:30Z", "to": "2021-12-13T17:00Z", "index": "VeryHigh", "forecast": 435},
+                {"from": "2021-12-13T17:00Z", "to": "2021-12-13T17:30Z", "index": "Low", "forecast": 120},
+            ])
+        );
+
+        let v1 =
+            window_message(FORECAST_WINDOW_TOPIC.to_string(), &slots, PayloadSchema::V1).unwrap();
+        let v1: serde_json::Value = serde_json::from_slice(&v1.payload).unwrap();
+        assert_eq!(v1[1]["forecast"], 120);
+        let v2 =
+            window_message(FORECAST_WINDOW_TOPIC.to_string(), &slots, PayloadSchema::V2).unwrap();
+        assert!(v2.retain);
+        let v2: serde_json::Value = serde_json::from_slice(&v2.payload).unwrap();
+        assert_eq!(v2["payload_schema_version"], 2);
+        assert_eq!(v2["slots"], v1);
+    }
+
+    #[test]
+    fn test_auto_region() {
+        let geo = |country_code: &str, zip: Option<&str>| GeoResponse {
+            country_code: country_code.to_string(),
+            zip: zip.map(str::to_string),
+        };
+        assert_eq!(outcode(&geo("GB", Some("EC1A 1BB"))), Ok("EC1A"));
+        assert_eq!(outcode(&geo("GB", Some("EC1A"))), Ok("EC1A"));
+        assert!(outcode(&geo("GB", Some(""))).is_err());
+        assert!(outcode(&geo("GB", None)).is_err());
+        assert!(outcode(&geo("DE", Some("10115"))).is_err());
+
+        let j = r#"{"data": [{"regionid": 13, "dnoregion": "UKPN London", "shortname": "London", "postcode": "EC1A", "data": []}]}"#;
+        let resp: RegionalResponse = serde_json::from_str(j).unwrap();
+        assert_eq!(postcode_region(resp).unwrap(), RegionId::London);
+    }
+
+    #[tokio::test]
+    async fn test_every_snapshot() {
+        let snapshot = |seq| Snapshot {
+            seq,
+            ..snapshot(reading(RegionId::London, "London", 435), Vec::new())
+        };
+        let (tx, rx) = SnapshotTx::new();
+        let mut latest = tx.subscribe(Delivery::Latest);
+        let mut every = tx.subscribe(Delivery::Every);
+        let mut last_seq = 0;
+        for seq in 1..=3 {
+            tx.send(Some(snapshot(seq)));
+        }
+        // Failed polls aren't events.
+        tx.send(None);
+        tx.send(Some(snapshot(4)));
+        for seq in 1..=4 {
+            assert_eq!(every.next("test", &mut last_seq).await.unwrap().seq, seq);
+        }
+        assert_eq!(latest.next("test", &mut last_seq).await.unwrap().seq, 4);
+
+        // Once it's too far behind, the oldest ones are lost.
+        let total = EVERY_SNAPSHOT_CAPACITY as u64 + 2;
+        for seq in 5..5 + total {
+            tx.send(Some(snapshot(seq)));
+        }
+        assert_eq!(every.next("test", &mut last_seq).await.unwrap().seq, 7);
+
+        // A restarted notifier only gets what's sent from then on.
+        let mut restarted = every.resubscribe();
+        tx.send(Some(snapshot(100)));
+        assert_eq!(
+            restarted.next("test", &mut last_seq).await.unwrap().seq,
+            100
+        );
+
+        // Notifiers that get every snapshot still count as listening.
+        drop((rx, latest));
+        assert!(!tx.is_closed());
+
+        drop(tx);
+        for seq in 8..5 + total {
+            assert_eq!(every.next("test", &mut last_seq).await.unwrap().seq, seq);
+        }
+        assert_eq!(every.next("test", &mut last_seq).await.unwrap().seq, 100);
+        assert!(every.next("test", &mut last_seq).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_next_snapshot() {
+        let snapshot = |seq| {
+            Some(Snapshot {
+                seq,
+                ..snapshot(reading(RegionId::London, "London", 435), Vec::new())
+            })
+        };
+        let (tx, mut rx) = tokio::sync::watch::channel(None);
+        let mut last_seq = 0;
+        let pending = tokio::time::timeout(
+            Duration::from_millis(50),
+            next_snapshot(&mut rx, "test", &mut last_seq),
+        );
+        tx.send_replace(None);
+        assert!(pending.await.is_err());
+
+        tx.send_replace(snapshot(1));
+        let got = next_snapshot(&mut rx, "test", &mut last_seq).await.unwrap();
+        assert_eq!(got.primary.intensity.forecast, GramsPerKwh(435));
+        assert_eq!(last_seq, 1);
+
+        // Updates that come in while the notifier is busy are coalesced, and
+        // only the latest is seen.
+        for seq in 2..=4 {
+            tx.send_replace(snapshot(seq));
+        }
+        let got = next_snapshot(&mut rx, "test", &mut last_seq).await.unwrap();
+        assert_eq!(got.seq, 4);
+        assert_eq!(last_seq, 4);
+
+        tx.send_replace(None);
+        drop(tx);
+        assert!(next_snapshot(&mut rx, "test", &mut last_seq)
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let mut smoother = Smoother::new(3);
+        let mut smooth = |region, forecast| {
+            let mut reading = reading(region, "", forecast);
+            smoother.apply(&mut reading);
+            reading
+        };
+
+        let first = smooth(RegionId::London, 100);
+        assert_eq!(first.intensity.forecast, GramsPerKwh(100));
+        assert_eq!(first.raw_forecast, Some(GramsPerKwh(100)));
+        assert_eq!(
+            smooth(RegionId::London, 200).intensity.forecast,
+            GramsPerKwh(150)
+        );
+        // Regions are smoothed separately.
+        assert_eq!(
+            smooth(RegionId::Wales, 40).intensity.forecast,
+            GramsPerKwh(40)
+        );
+        let third = smooth(RegionId::London, 330);
+        assert_eq!(third.intensity.forecast, GramsPerKwh(210));
+        assert_eq!(third.intensity.index, Intensity::High);
+        assert_eq!(third.raw_forecast, Some(GramsPerKwh(330)));
+        // The oldest sample drops out of the window.
+        assert_eq!(
+            smooth(RegionId::London, 330).intensity.forecast,
+            GramsPerKwh(287)
+        );
+    }
+
+    /// Serves `LONDON_FIXTURE` on a local port, or errors while `failing`
+    /// is set. Returns the base URL.
+    fn serve_fixture(failing: std::sync::Arc<std::sync::atomic::AtomicBool>) -> String {
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let failing = failing.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |_req| {
+                    let mut response = hyper::Response::new(hyper::Body::from(LONDON_FIXTURE));
+                    if failing.load(std::sync::atomic::Ordering::Relaxed) {
+                        *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = hyper::Body::from("oops");
+                    }
+                    async move { Ok::<_, std::convert::Infallible>(response) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let primary_down = Arc::new(AtomicBool::new(true));
+        let primary = serve_fixture(primary_down.clone());
+        let fallback = serve_fixture(Arc::new(AtomicBool::new(false)));
+        let config = try_parse_config(&format!(
+            r#"api_base_url: "{}", api_fallback_url: Some("{}"), api_failover_retry: (attempts: 1),"#,
+            primary, fallback
+        ))
+        .unwrap();
+        let client = reqwest::Client::new();
+        let regions = [RegionId::London];
+        let mut on_fallback = false;
+
+        let shared = Shared::default();
+        let readings = fetch_with_failover(
+            &client,
+            &client,
+            &config,
+            &shared,
+            &regions,
+            &mut on_fallback,
+        )
+        .await
+        .unwrap();
+        assert_eq!(readings[0].shortname, "London");
+        assert!(on_fallback);
+        assert_eq!(shared.counters.values().failovers, 1);
+
+        // It stays on the fallback while the primary is down...
+        fetch_with_failover(
+            &client,
+            &client,
+            &config,
+            &shared,
+            &regions,
+            &mut on_fallback,
+        )
+        .await
+        .unwrap();
+        assert!(on_fallback);
+
+        // ...and switches back once it's up again.
+        primary_down.store(false, Ordering::Relaxed);
+        fetch_with_failover(
+            &client,
+            &client,
+            &config,
+            &shared,
+            &regions,
+            &mut on_fallback,
+        )
+        .await
+        .unwrap();
+        assert!(!on_fallback);
+    }
+
+    #[tokio::test]
+    async fn test_failover_recovers() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let primary_down = Arc::new(AtomicBool::new(true));
+        let fallback_down = Arc::new(AtomicBool::new(true));
+        let primary = serve_fixture(primary_down.clone());
+        let fallback = serve_fixture(fallback_down.clone());
+        let config = try_parse_config(&format!(
+            r#"api_base_url: "{}", api_fallback_url: Some("{}"), api_failover_retry: (attempts: 1),"#,
+            primary, fallback
+        ))
+        .unwrap();
+        let shared = Arc::new(Shared::default());
+        let poller = Poller::new(config, shared.clone()).unwrap();
+        // Without waiting in between, so that each poll happens on demand.
+        let stream = poll_loop(poller, Duration::ZERO, Duration::ZERO);
+        futures_util::pin_mut!(stream);
+
+        // With both down, the poll fails, but polling goes on...
+        assert!(stream.next().await.unwrap().is_err());
+        assert_eq!(shared.counters.values().failovers, 1);
+        // ...and picks up the fallback once it's back...
+        fallback_down.store(false, Ordering::Relaxed);
+        assert_eq!(stream.next().await.unwrap().unwrap().seq, 1);
+        // ...and then the primary, without failing over again.
+        primary_down.store(false, Ordering::Relaxed);
+        fallback_down.store(true, Ordering::Relaxed);
+        assert_eq!(stream.next().await.unwrap().unwrap().seq, 2);
+        assert_eq!(stream.next().await.unwrap().unwrap().seq, 3);
+        assert_eq!(shared.counters.values().failovers, 1);
+    }
+
+    #[tokio::test]
+    async fn test_poll_once() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+
+        let base_url = serve_fixture(Arc::new(AtomicBool::new(false)));
+        let config = try_parse_config(&format!(r#"api_base_url: "{}","#, base_url)).unwrap();
+        let mut poller = Poller::new(config, Default::default()).unwrap();
+        let snapshot = poller.poll_once().await.unwrap();
+        assert_eq!(snapshot.primary.shortname, "London");
+        assert_eq!(snapshot.seq, 1);
+        assert_eq!(poller.poll_once().await.unwrap().seq, 2);
+    }
+
+    #[tokio::test]
+    async fn test_poll_on_thread() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+
+        let base_url = serve_fixture(Arc::new(AtomicBool::new(false)));
+        let config = try_parse_config(&format!(
+            r#"api_base_url: "{}", dedicated_poll_thread: true,"#,
+            base_url
+        ))
+        .unwrap();
+        let stream = poll_on_thread(config, Default::default());
+        futures_util::pin_mut!(stream);
+        let snapshot = stream.next().await.unwrap().unwrap();
+        assert_eq!(snapshot.primary.shortname, "London");
+        assert_eq!(snapshot.seq, 1);
+    }
+
+    #[test]
+    fn test_staleness() {
+        let snapshot = snapshot(reading(RegionId::London, "London", 435), Vec::new());
+        let now = polled_at() + chrono::Duration::seconds(300);
+        assert_eq!(snapshot.staleness_secs(now), 300);
+        // Clocks can go backwards.
+        assert_eq!(
+            snapshot.staleness_secs(polled_at() - chrono::Duration::seconds(1)),
+            0
+        );
+
+        let topics = try_parse_config("").unwrap().topics();
+        let payload = |schema| {
+            let encoding = Encoding {
+                format: PayloadFormat::Json,
+                schema,
+            };
+            let message =
+                state_message(&snapshot, &topics, encoding, Duration::from_secs(3600), now)
+                    .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&message.payload).unwrap()
+        };
+        let v2 = payload(PayloadSchema::V2);
+        assert_eq!(v2["staleness_secs"], 300);
+        // Retained, it's only right as of publishing, so the time of the poll
+        // goes along with it.
+        assert_eq!(v2["fetched_at"], "2021-12-13T16:40:00Z");
+        assert_eq!(v2["shortname"], "London");
+        assert!(payload(PayloadSchema::V1).get("staleness_secs").is_none());
+
+        let ctx = RenderCtx {
+            staleness_secs: Some(300),
+            ..RenderCtx::default()
+        };
+        assert_eq!(
+            render(
+                "{staleness}s old",
+                &snapshot.primary.intensity,
+                "London",
+                &ctx
+            ),
+            "300s old"
+        );
+    }
+
+    /// Takes a second per sample, and fails on the ones in `failing`.
+    struct FakeSampler {
+        seq: u64,
+        failing: &'static [u64],
+    }
+
+    impl Sample for FakeSampler {
+        fn sample(
+            &mut self,
+        ) -> futures_util::future::LocalBoxFuture<'_, Result<Snapshot, Box<dyn std::error::Error>>>
+        {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                self.seq += 1;
+                if self.failing.contains(&self.seq) {
+                    return Err("down".into());
+                }
+                let mut snapshot = snapshot(reading(RegionId::London, "London", 100), Vec::new());
+                // Current, so that it doesn't count as outdated.
+                snapshot.primary.to = chrono::Utc::now() + chrono::Duration::minutes(30);
+                snapshot.seq = self.seq;
+                Ok(snapshot)
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_poll_loop() {
+        use futures_util::StreamExt;
+
+        let start = tokio::time::Instant::now();
+        let stream = poll_loop(
+            FakeSampler {
+                seq: 0,
+                failing: &[3, 4, 7],
+            },
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+        )
+        .take(8);
+        futures_util::pin_mut!(stream);
+        let mut samples = vec![];
+        while let Some(res) = stream.next().await {
+            samples.push((res.ok().map(|s| s.seq), start.elapsed().as_secs()));
+        }
+        // Intervals count from when the last sample finished. Failures are
+        // retried sooner, backing off while they last, and polling picks up
+        // again afterwards.
+        assert_eq!(
+            samples,
+            [
+                (Some(1), 11),
+                (Some(2), 72),
+                (None, 133),
+                (None, 149),
+                (Some(5), 180),
+                (Some(6), 241),
+                (None, 302),
+                (Some(8), 318),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_poll_status_recovers() {
+        use futures_util::StreamExt;
+
+        let (tx, rx) = SnapshotTx::new();
+        let recorder = Recorder::default();
+        let poll = async move {
+            let stream = poll_loop(
+                FakeSampler {
+                    seq: 0,
+                    failing: &[2],
+                },
+                Duration::ZERO,
+                Duration::from_secs(60),
+            )
+            .take(3);
+            futures_util::pin_mut!(stream);
+            while let Some(res) = stream.next().await {
+                tx.send(res.ok());
+            }
+        };
+        tokio::join!(
+            publish_poll_status(&recorder, "status".to_string(), false, rx),
+            poll
+        );
+        let published: Vec<_> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| String::from_utf8_lossy(&m.payload).into_owned())
+            .collect();
+        assert_eq!(published, ["online", "offline", "online"]);
+    }
+
+    #[tokio::test]
+    async fn test_relay_locks() {
+        let locks = RelayLocks::default();
+        let first = locks.get("/regional");
+        let guard = first.lock().await;
+        // Other paths aren't held up...
+        let other = locks.get("/regional/regionid/13");
+        assert!(other.try_lock().is_ok());
+        locks.release("/regional/regionid/13", other);
+        // ...but the same one waits.
+        let second = locks.get("/regional");
+        assert!(second.try_lock().is_err());
+        drop(guard);
+        locks.release("/regional", first);
+        assert_eq!(locks.0.lock().unwrap().len(), 1);
+        locks.release("/regional", second);
+        assert!(locks.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_relay_cache() {
+        use chrono::TimeZone;
+        let t = |h, m| chrono::Utc.ymd(2021, 12, 13).and_hms(h, m, 0);
+        assert_eq!(next_half_hour(t(16, 10)), t(16, 30));
+        assert_eq!(next_half_hour(t(16, 30)), t(17, 0));
+
+        let mut cache = RelayCache::new(2);
+        cache.insert(
+            "/regional/regionid/13".to_string(),
+            "{}".to_string(),
+            t(16, 10),
+        );
+        assert_eq!(cache.get("/regional/regionid/13", t(16, 29)), Some("{}"));
+        assert_eq!(cache.get("/regional/regionid/12", t(16, 29)), None);
+        assert_eq!(cache.get("/regional/regionid/13", t(16, 30)), None);
+
+        // Expired entries go once something else is cached.
+        cache.insert("/regional".to_string(), "[]".to_string(), t(16, 45));
+        assert_eq!(cache.entries.len(), 1);
+
+        // Beyond the limit, the oldest entries make way.
+        cache.insert("/a".to_string(), "a".to_string(), t(16, 46));
+        cache.insert("/regional".to_string(), "[]".to_string(), t(16, 47));
+        cache.insert("/b".to_string(), "b".to_string(), t(16, 48));
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get("/a", t(16, 49)), None);
+        assert_eq!(cache.get("/b", t(16, 49)), Some("b"));
+    }
+
+    #[test]
+    fn test_buffer_limits() {
+        let start = Instant::now();
+        let mut alerts = AlertState::new(start, Duration::from_secs(0))
+            .with_rate_alert(GramsPerKwh(50), Duration::from_secs(3600))
+            .with_max_entries(3);
+        for secs in 0..10 {
+            let intensity = IntensityResponse {
+                index: Intensity::Moderate,
+                forecast: GramsPerKwh(150),
+                actual: None,
+                forecast_low: None,
+                forecast_high: None,
+            };
+            alerts.decide(&intensity, start + Duration::from_secs(secs));
+        }
+        assert_eq!(alerts.recent.len(), 3);
+
+        let sizes = BufferSizes::default();
+        let mut smoother = Smoother::new(100)
+            .with_max_entries(2)
+            .with_buffer_size(sizes.smoothing.clone());
+        for forecast in [100, 200, 300] {
+            smoother.apply(&mut reading(RegionId::London, "London", forecast));
+        }
+        assert_eq!(smoother.recent[&RegionId::London].len(), 2);
+        assert_eq!(sizes.smoothing.get(), 2);
+        drop(smoother);
+        assert_eq!(sizes.smoothing.get(), 0);
+
+        // Forecasts for slots too far back are dropped, however many fit.
+        let mut smoother = Smoother::new(10).with_max_age(Duration::from_secs(3600));
+        let mut old = reading(RegionId::London, "London", 100);
+        smoother.apply(&mut old);
+        let mut new = reading(RegionId::London, "London", 300);
+        new.from = new.from + chrono::Duration::minutes(90);
+        smoother.apply(&mut new);
+        assert_eq!(new.intensity.forecast, GramsPerKwh(300));
+        assert_eq!(smoother.recent[&RegionId::London].len(), 1);
+
+        // So are cached responses.
+        use chrono::TimeZone;
+        let t = |h, m| chrono::Utc.ymd(2021, 12, 13).and_hms(h, m, 0);
+        let mut cache = RelayCache::new(10).with_max_age(Duration::from_secs(5 * 60));
+        cache.insert("/regional".to_string(), "[]".to_string(), t(16, 10));
+        assert_eq!(cache.get("/regional", t(16, 14)), Some("[]"));
+        assert_eq!(cache.get("/regional", t(16, 15)), None);
+
+        let config = parse_config(
+            "max_buffer_entries: 100, buffer_limits: (relay_cache: Some(5)), max_buffer_age_secs: Some(600),",
+        );
+        config.validate().unwrap();
+        assert_eq!(config.buffer_limit(config.buffer_limits.relay_cache), 5);
+        assert_eq!(config.buffer_limit(config.buffer_limits.smoothing), 100);
+        let err = parse_config("buffer_limits: (smoothing: Some(0)),")
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "buffer_limits.smoothing must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_fuel_shares() {
+        let reading = parse_reading(LONDON_FIXTURE).unwrap();
+        let mix = &reading.generation_mix;
+        assert_eq!(mix.len(), 9);
+
+        let renewables = default_renewable_fuels();
+        // Wind 4.3, hydro 0.2, solar and biomass nothing.
+        assert!((fuel_share(mix, &renewables) - 4.5).abs() < 1e-9);
+        // Plus nuclear 2.4.
+        assert!((low_carbon_share(mix, &renewables) - 6.9).abs() < 1e-9);
+
+        let wind_only = vec!["wind".to_string()];
+        assert!((fuel_share(mix, &wind_only) - 4.3).abs() < 1e-9);
+        assert!((low_carbon_share(mix, &wind_only) - 6.7).abs() < 1e-9);
+        assert_eq!(fuel_share(&[], &renewables), 0.0);
     }
 }